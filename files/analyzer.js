@@ -6,8 +6,8 @@ function escapeRegExp(stringToGoIntoTheRegex) {
     return stringToGoIntoTheRegex.replace(/[-\/\\^$*+?.()|[\]{}]/g, '\\$&');
 }
 
-const hrefRegex = new RegExp("^http(s)?:\/\/"+escapeRegExp(window.location.hostname.replace(/\/$/, "")) + "(:[\\d]+)?\\/(s\/)?[A-z\\d]{3}(\\.[A-z\\d]+)?$"); // if this regex matches, the URL is correct.
-const shortRegex = new RegExp("^http(s)?:\/\/"+escapeRegExp(window.location.hostname.replace(/\/$/, "")) + "(:[\\d]+)?\\/s\/[A-z\\d]{3}(\\.[A-z\\d]+)?$"); // if this regex matches, the URL is correct.
+const hrefRegex = new RegExp("^http(s)?:\/\/"+escapeRegExp(window.location.hostname.replace(/\/$/, "")) + "(:[\\d]+)?\\/(s\/)?[A-z\\d]+(\\.[A-z\\d]+)?$"); // if this regex matches, the URL is correct.
+const shortRegex = new RegExp("^http(s)?:\/\/"+escapeRegExp(window.location.hostname.replace(/\/$/, "")) + "(:[\\d]+)?\\/s\/[A-z\\d]+(\\.[A-z\\d]+)?$"); // if this regex matches, the URL is correct.
 
 let lock = false
 
@@ -40,7 +40,7 @@ const lookUpLink = async (link) => {
       const linkData = await response.text()
       linkInput.value = ''
       linkInput.placeholder = 'Link to look up...'
-      const outRegex = /(Views: )?(\d*)[\n ]?(Scrapes: )?(\d*)[ \n]?(Created: )?(\d{2}\/\d{2}\/\d{4,} \d{2}:\d{2})?/
+      const outRegex = /(Views: )?(\d*)[\n ]?(Scrapes: )?(\d*)[ \n]?(Created: )?(\d{2}\/\d{2}\/\d{4,} \d{2}:\d{2})?[ \n]?(Last viewed: )?(\d{2}\/\d{2}\/\d{4,} \d{2}:\d{2}|Never)?/
       var views = linkData.replace(outRegex, "$2")
       var scrapes = linkData.replace(outRegex, "$4")
       var date = linkData.replace(outRegex, "$6")
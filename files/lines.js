@@ -0,0 +1,35 @@
+// Highlights the lines in the fragment of a paste page, like #L10 or #L10-L20. Shift-clicking a
+// line number extends the highlighted range to it.
+
+const selectedRange = () => {
+  const match = window.location.hash.match(/^#L(\d+)(?:-L?(\d+))?$/)
+  if (!match) return null
+  const start = Number(match[1])
+  const end = match[2] ? Number(match[2]) : start
+  return [Math.min(start, end), Math.max(start, end)]
+}
+
+const highlightLines = () => {
+  document.querySelectorAll('.ln.hl').forEach(line => line.classList.remove('hl'))
+  const range = selectedRange()
+  if (!range) return
+  for (let n = range[0]; n <= range[1]; n++) {
+    const line = document.getElementById('L' + n)
+    if (line) line.classList.add('hl')
+  }
+  // A range isn't the ID of any element, so the browser doesn't scroll to it by itself
+  const first = document.getElementById('L' + range[0])
+  if (first) first.scrollIntoView({ block: 'center' })
+}
+
+document.addEventListener('click', ev => {
+  const line = ev.target.closest && ev.target.closest('.ln')
+  const range = selectedRange()
+  if (!line || !ev.shiftKey || !range) return
+  ev.preventDefault()
+  const n = Number(line.dataset.line)
+  window.location.hash = `#L${Math.min(range[0], n)}-L${Math.max(range[0], n)}`
+})
+
+window.addEventListener('hashchange', highlightLines)
+highlightLines()
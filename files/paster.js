@@ -1,6 +1,49 @@
 const pasteInput = document.getElementById('input')
 const pasteSubmit = document.getElementById('paste')
 const filetypeInput = document.getElementById('filetype')
+const encryptInput = document.getElementById('encrypt')
+
+// Encrypted pastes are "OXE", the format version, a 12 byte nonce and the AES-256-GCM
+// ciphertext, see src/crypto.rs. The key never leaves the browser, it is kept in the link
+// after a '!' in the fragment.
+const ENCRYPTED_HEADER = new Uint8Array([0x4f, 0x58, 0x45, 0x01])
+
+const toBase64Url = (bytes) =>
+    btoa(String.fromCharCode(...bytes)).replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '')
+
+const fromBase64Url = (text) =>
+    Uint8Array.from(atob(text.replace(/-/g, '+').replace(/_/g, '/')), c => c.charCodeAt(0))
+
+const encrypt = async (text) => {
+    const key = crypto.getRandomValues(new Uint8Array(32))
+    const nonce = crypto.getRandomValues(new Uint8Array(12))
+    const cryptoKey = await crypto.subtle.importKey('raw', key, 'AES-GCM', false, ['encrypt'])
+    const ciphertext = new Uint8Array(await crypto.subtle.encrypt(
+        { name: 'AES-GCM', iv: nonce, additionalData: ENCRYPTED_HEADER },
+        cryptoKey,
+        new TextEncoder().encode(text),
+    ))
+    const encrypted = new Uint8Array(ENCRYPTED_HEADER.length + nonce.length + ciphertext.length)
+    encrypted.set(ENCRYPTED_HEADER)
+    encrypted.set(nonce, ENCRYPTED_HEADER.length)
+    encrypted.set(ciphertext, ENCRYPTED_HEADER.length + nonce.length)
+    return { encrypted, key: toBase64Url(key) }
+}
+
+const decrypt = async (encrypted, key) => {
+    const header = encrypted.slice(0, ENCRYPTED_HEADER.length)
+    if (!header.every((byte, i) => byte === ENCRYPTED_HEADER[i])) {
+        throw new Error('not an encrypted paste')
+    }
+    const nonce = encrypted.slice(ENCRYPTED_HEADER.length, ENCRYPTED_HEADER.length + 12)
+    const cryptoKey = await crypto.subtle.importKey('raw', fromBase64Url(key), 'AES-GCM', false, ['decrypt'])
+    const plaintext = await crypto.subtle.decrypt(
+        { name: 'AES-GCM', iv: nonce, additionalData: ENCRYPTED_HEADER },
+        cryptoKey,
+        encrypted.slice(ENCRYPTED_HEADER.length + 12),
+    )
+    return new TextDecoder().decode(plaintext)
+}
 
 function escapeRegExp(stringToGoIntoTheRegex) {
     return stringToGoIntoTheRegex.replace(/[-\/\\^$*+?.()|[\]{}]/g, '\\$&');
@@ -15,24 +58,42 @@ window.addEventListener('DOMContentLoaded', (_) => {
     let paste = document.location.hash.replace("#","")
 
     let ext = "";
+    let key = "";
+
+    if (paste.includes("!")){
+        key = paste.slice(paste.indexOf("!") + 1)
+        paste = paste.slice(0,paste.indexOf("!"))
+    }
 
     if (paste.includes(".")){
         ext = paste.slice(paste.lastIndexOf(".")).replace(".","")
         paste = paste.slice(0,paste.lastIndexOf("."))
     }
 
-    get_paste(paste, ext);
+    get_paste(paste, ext, key);
 });
 
-const get_paste = async (paste, ext) => {
+const get_paste = async (paste, ext, key) => {
     if (paste.length < 1) {
         return;
     }
     let input = document.getElementById('input');
     let filetype = document.getElementById('filetype');
-    const response = await fetch("/" + paste)
+    const response = await fetch((key ? "/r/" : "/") + paste)
     if (response.ok) {
-        const text = await response.text();
+        let text;
+        if (key) {
+            try {
+                text = await decrypt(new Uint8Array(await response.arrayBuffer()), key)
+            } catch (error) {
+                input.classList.add("error")
+                input.placeholder = "Could not decrypt `" + paste + "`, the key in the link is wrong"
+                return
+            }
+            encryptInput.checked = true
+        } else {
+            text = await response.text();
+        }
         input.textContent = text;
         filetype.value = ext;
     } else {
@@ -61,13 +122,22 @@ const make_paste = async (paste) => {
 
     pasteInput.value = ''
     pasteInput.placeholder = 'Generating paste...'
+    let headers = {
+        'Content-Type': 'text/plain; charset=utf-8'
+    }
+    let body = paste
+    let key = ""
+    if (encryptInput.checked) {
+        ({ encrypted: body, key } = await encrypt(paste))
+        headers = {
+            'Content-Type': 'application/octet-stream',
+            'X-Encrypted': '1',
+        }
+    }
     const response = await fetch('/', {
       method: 'POST',
-      headers: {
-        'Content-Type': 'text/plain; charset=utf-8'
-      },
-      body:
-        paste,
+      headers,
+      body,
       })
     if (response.ok) {
       pasteInput.classList.remove('error')
@@ -77,6 +147,11 @@ const make_paste = async (paste) => {
         } else {
             pasteData = pasteData+ "." + filetypeInput.value
         }
+        if (key) {
+            // Encrypted pastes are decrypted by this page, the server never sees the key
+            const url = new URL(pasteData)
+            pasteData = url.origin + "/#" + url.pathname.slice(1) + "!" + key
+        }
       pasteInput.value = pasteData
       pasteInput.placeholder = 'Data to paste...'
       pasteInput.select()
@@ -99,8 +174,8 @@ const linkSubmit = document.getElementById('submit-info')
 const infoSplash = document.getElementById('splash')
 
 
-const hrefRegex = new RegExp("^http(s)?:\/\/"+escapeRegExp(window.location.hostname.replace(/\/$/, "")) + "(:[\\d]+)?\\/(s\/)?[A-z\\d]{3}(\\.[A-z\\d]+)?$"); // if this regex matches, the URL is correct.
-const shortRegex = new RegExp("^http(s)?:\/\/"+escapeRegExp(window.location.hostname.replace(/\/$/, "")) + "(:[\\d]+)?\\/s\/[A-z\\d]{3}(\\.[A-z\\d]+)?$"); // if this regex matches, the URL is correct.
+const hrefRegex = new RegExp("^http(s)?:\/\/"+escapeRegExp(window.location.hostname.replace(/\/$/, "")) + "(:[\\d]+)?\\/(s\/)?[A-z\\d]+(\\.[A-z\\d]+)?$"); // if this regex matches, the URL is correct.
+const shortRegex = new RegExp("^http(s)?:\/\/"+escapeRegExp(window.location.hostname.replace(/\/$/, "")) + "(:[\\d]+)?\\/s\/[A-z\\d]+(\\.[A-z\\d]+)?$"); // if this regex matches, the URL is correct.
 
 let infoLock = false
 
@@ -133,7 +208,7 @@ const lookUpLink = async (link) => {
       const linkData = await response.text()
       linkInput.value = ''
       linkInput.placeholder = 'Link to look up...'
-      const outRegex = /(Views: )?(\d*)[\n ]?(Scrapes: )?(\d*)[ \n]?(Created: )?(\d{2}\/\d{2}\/\d{4,} \d{2}:\d{2})?/
+      const outRegex = /(Views: )?(\d*)[\n ]?(Scrapes: )?(\d*)[ \n]?(Created: )?(\d{2}\/\d{2}\/\d{4,} \d{2}:\d{2})?[ \n]?(Last viewed: )?(\d{2}\/\d{2}\/\d{4,} \d{2}:\d{2}|Never)?/
       var views = linkData.replace(outRegex, "$2")
       var scrapes = linkData.replace(outRegex, "$4")
       var date = linkData.replace(outRegex, "$6")
@@ -0,0 +1,262 @@
+//! JSON documents sent to clients asking for `application/json`
+
+use crate::config::Config;
+use crate::state::{Counters, Entry};
+use crate::util::ClientType;
+use axum::body::HttpBody;
+use axum::http::header::{self, HeaderName};
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+
+pub static DELETE_TOKEN_HEADER: &str = "x-delete-token";
+
+/// Sent back after creating a paste or short link
+#[derive(Serialize)]
+pub struct Created<'a> {
+    pub id: &'a str,
+    pub url: String,
+    /// Always serves the paste as plain text, whatever the client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    /// Where a short link redirects to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<&'a str>,
+    pub delete_token: &'a str,
+    /// The number of the revision created when a paste was edited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<u32>,
+    pub size: u64,
+    pub truncated: bool,
+    pub created: i64,
+    pub expires: Option<i64>,
+}
+
+impl<'a> Created<'a> {
+    pub fn paste(
+        config: &Config,
+        id: &'a str,
+        token: &'a str,
+        entry: &'a Entry,
+        truncated: bool,
+    ) -> Self {
+        let ext = entry
+            .extension()
+            .map(|ext| format!(".{ext}"))
+            .unwrap_or_default();
+        Created {
+            id,
+            url: format!("{}/{id}{ext}", config.public_url),
+            raw_url: Some(format!("{}/r/{id}", config.public_url)),
+            image_url: Some(format!("{}/i/{id}{ext}", config.public_url)),
+            target: None,
+            filename: entry.filename.as_deref(),
+            content_type: Some(&entry.content_type),
+            delete_token: token,
+            revision: None,
+            size: entry.size(),
+            truncated,
+            created: entry.creationdate,
+            expires: entry.expiry,
+        }
+    }
+    pub fn url(config: &Config, id: &'a str, token: &'a str, entry: &'a Entry) -> Self {
+        Created {
+            id,
+            url: format!("{}/s/{id}", config.public_url),
+            raw_url: None,
+            image_url: None,
+            target: std::str::from_utf8(&entry.contents).ok(),
+            filename: None,
+            content_type: None,
+            delete_token: token,
+            revision: None,
+            size: entry.size(),
+            truncated: false,
+            created: entry.creationdate,
+            expires: entry.expiry,
+        }
+    }
+}
+
+/// Builds the response to a successful creation: the URLs in plaintext, one per line, or a JSON
+/// document if the client asked for one, an array if more than one entry was created. The delete
+/// tokens are always sent in `X-Delete-Token` headers, in the same order.
+pub fn created_response(status: StatusCode, headers: &HeaderMap, created: &[Created]) -> Response {
+    let mut response = if ClientType::from(headers) != ClientType::Json {
+        let urls: Vec<&str> = created.iter().map(|created| created.url.as_str()).collect();
+        (status, urls.join("\n")).into_response()
+    } else if let [created] = created {
+        (status, Json(created)).into_response()
+    } else {
+        (status, Json(created)).into_response()
+    };
+    for created in created {
+        if let Ok(token) = HeaderValue::from_str(created.delete_token) {
+            response
+                .headers_mut()
+                .append(HeaderName::from_static(DELETE_TOKEN_HEADER), token);
+        }
+    }
+    response
+}
+
+/// Metadata and analytics of a paste or short link
+#[derive(Serialize)]
+pub struct Info<'a> {
+    pub id: &'a str,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<&'a str>,
+    /// The syntax detected for a paste, see `language`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<&'a str>,
+    pub size: u64,
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub burn_after_reading: bool,
+    pub encrypted: bool,
+    /// `last_viewed` is 0 if the entry was never viewed
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+impl<'a> Info<'a> {
+    pub fn paste(config: &Config, id: &'a str, entry: &'a Entry, counters: Counters) -> Self {
+        let ext = entry
+            .extension()
+            .map(|ext| format!(".{ext}"))
+            .unwrap_or_default();
+        Info {
+            id,
+            url: format!("{}/{id}{ext}", config.public_url),
+            raw_url: Some(format!("{}/r/{id}", config.public_url)),
+            image_url: Some(format!("{}/i/{id}{ext}", config.public_url)),
+            target: None,
+            filename: entry.filename.as_deref(),
+            content_type: Some(&entry.content_type),
+            language: entry.language.as_deref(),
+            size: entry.size(),
+            created: entry.creationdate,
+            expires: entry.expiry,
+            burn_after_reading: entry.burn_after_reading,
+            encrypted: entry.encrypted,
+            counters,
+        }
+    }
+    pub fn url(config: &Config, id: &'a str, entry: &'a Entry, counters: Counters) -> Self {
+        Info {
+            id,
+            url: format!("{}/s/{id}", config.public_url),
+            raw_url: None,
+            image_url: None,
+            target: std::str::from_utf8(&entry.contents).ok(),
+            filename: None,
+            content_type: None,
+            language: None,
+            size: entry.size(),
+            created: entry.creationdate,
+            expires: entry.expiry,
+            burn_after_reading: false,
+            encrypted: false,
+            counters,
+        }
+    }
+}
+
+/// A language pastes can be highlighted in, as listed by `/languages`
+#[derive(Serialize)]
+pub struct Language<'a> {
+    pub name: &'a str,
+    /// The extensions that pick it, in the URL of a paste or the name of an uploaded file
+    pub extensions: &'a [String],
+}
+
+/// One revision of a paste, as listed by `/v/:paste`
+#[derive(Serialize)]
+pub struct Revision<'a> {
+    pub revision: u32,
+    pub url: String,
+    pub raw_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<&'a str>,
+    pub content_type: &'a str,
+    pub size: u64,
+    pub created: i64,
+}
+
+impl<'a> Revision<'a> {
+    pub fn new(config: &Config, id: &str, revision: u32, entry: &'a Entry) -> Self {
+        Revision {
+            revision,
+            url: format!("{}/{id}@{revision}", config.public_url),
+            raw_url: format!("{}/r/{id}@{revision}", config.public_url),
+            filename: entry.filename.as_deref(),
+            content_type: &entry.content_type,
+            size: entry.size(),
+            created: entry.creationdate,
+        }
+    }
+}
+
+/// The number of entries stored, as served by `/count`
+#[derive(Serialize)]
+pub struct Count {
+    pub urls: u64,
+    pub pastes: u64,
+}
+
+/// An error, the message being the plaintext body the handler responded with
+#[derive(Serialize)]
+pub struct Error {
+    pub status: u16,
+    pub error: String,
+}
+
+/// Middleware turning plaintext error responses into `Error` documents for JSON clients
+pub async fn json_errors<B>(request: Request<B>, next: Next<B>) -> Response {
+    let json = ClientType::from(request.headers()) == ClientType::Json;
+    let response = next.run(request).await;
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map_or(false, |content_type| {
+            content_type.as_bytes().starts_with(b"application/json")
+        });
+    if !json || is_json || !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+    let (mut parts, mut body) = response.into_parts();
+    let mut message = Vec::new();
+    while let Some(Ok(chunk)) = body.data().await {
+        message.extend_from_slice(&chunk);
+    }
+    let mut error = String::from_utf8_lossy(&message).trim().to_string();
+    if error.is_empty() {
+        error = status.canonical_reason().unwrap_or_default().to_string();
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.remove(header::CONTENT_TYPE);
+    let error = Json(Error {
+        status: status.as_u16(),
+        error,
+    });
+    (parts, error).into_response()
+}
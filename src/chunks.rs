@@ -0,0 +1,312 @@
+//! Pastes too large for a single record. The first `chunk_size` bytes of a paste are kept in its
+//! record, the rest is split into chunks in the chunk column family while it is uploaded.
+
+use crate::id::Id;
+use crate::state::{CurState, PasteBody};
+use crate::StatusCode;
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap};
+use rkyv::{Archive, Deserialize, Serialize};
+use std::ops::Range;
+
+/// Where the part of a paste that didn't fit in its record is stored
+#[derive(Archive, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[archive(check_bytes)]
+pub struct Chunks {
+    /// The chunks are stored under this key followed by their index
+    pub key: String,
+    pub count: u32,
+    /// The size of every chunk but the last one
+    pub chunk_size: u32,
+    /// The size of the whole paste, including the part kept in its record
+    pub size: u64,
+}
+
+impl Chunks {
+    /// The bytes of the paste held by a chunk, `head` being the size of the part in the record
+    fn range(&self, head: u64, index: u32) -> Range<u64> {
+        let start = head + index as u64 * self.chunk_size as u64;
+        start..(start + self.chunk_size as u64).min(self.size)
+    }
+}
+
+/// The key chunk `index` of `key` is stored under
+pub fn chunk_key(key: &str, index: u32) -> Vec<u8> {
+    [key.as_bytes(), b"/", &index.to_be_bytes()].concat()
+}
+
+/// The keys of all chunks of `key`, '0' being the byte after '/'
+pub fn chunk_keys(key: &str) -> Range<Vec<u8>> {
+    [key.as_bytes(), b"/"].concat()..[key.as_bytes(), b"0"].concat()
+}
+
+/// Bytes held back from being written as a chunk, so a truncated paste can still be cut back to
+/// the start of the character the size limit fell in
+const HOLD_BACK: usize = 3;
+
+/// Collects an upload as it is received, writing every full chunk past the first `chunk_size`
+/// bytes straight to the database so large pastes are never buffered whole, and hashing it.
+/// Chunks are written on a blocking thread, like all database I/O of async handlers.
+///
+/// The chunks written are deleted again if the writer is dropped before it is finished.
+pub struct ChunkWriter<'a> {
+    state: &'a CurState,
+    key: String,
+    chunk_size: usize,
+    limit: usize,
+    /// Whether to cut the paste at the size limit instead of rejecting it
+    truncate: bool,
+    /// The part of the paste kept in its record
+    head: Vec<u8>,
+    /// Received bytes that weren't written as a chunk yet
+    pending: Vec<u8>,
+    count: u32,
+    size: usize,
+    truncated: bool,
+    finished: bool,
+    hasher: blake3::Hasher,
+    /// The head is only hashed once it is final, before the first chunk
+    head_hashed: bool,
+}
+
+impl<'a> ChunkWriter<'a> {
+    pub fn new(state: &'a CurState, truncate: bool) -> Self {
+        ChunkWriter {
+            state,
+            key: Id::token(),
+            chunk_size: state.config.chunk_size,
+            limit: state.config.max_paste_bytes,
+            truncate,
+            head: Vec::new(),
+            pending: Vec::new(),
+            count: 0,
+            size: 0,
+            truncated: false,
+            finished: false,
+            hasher: blake3::Hasher::new(),
+            head_hashed: false,
+        }
+    }
+    /// Lowers the size limit, for uploads of several files that share one
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = self.limit.min(limit);
+        self
+    }
+    /// Adds received data to the paste, returning whether more is wanted. Fails with 413 once the
+    /// paste grows past the size limit, unless it may be truncated.
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<bool, (StatusCode, &'static str)> {
+        if self.truncated {
+            return Ok(false);
+        }
+        let room = self.limit - self.size;
+        if data.len() > room {
+            if !self.truncate {
+                return Err((
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "Paste is larger than the size limit",
+                ));
+            }
+            data = &data[..room];
+            self.truncated = true;
+        }
+        self.size += data.len();
+        let head = data.len().min(self.chunk_size - self.head.len());
+        self.head.extend_from_slice(&data[..head]);
+        self.pending.extend_from_slice(&data[head..]);
+        while self.pending.len() >= self.chunk_size + HOLD_BACK {
+            self.flush(self.chunk_size).await?;
+        }
+        Ok(!self.truncated)
+    }
+    /// Writes the first `length` pending bytes as the next chunk
+    async fn flush(&mut self, length: usize) -> Result<(), (StatusCode, &'static str)> {
+        let chunk: Vec<u8> = self.pending.drain(..length).collect();
+        if !self.head_hashed {
+            self.hasher.update(&self.head);
+            self.head_hashed = true;
+        }
+        self.hasher.update(&chunk);
+        let failed = (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to store the paste",
+        );
+        let (key, index) = (self.key.clone(), self.count);
+        let stored = self
+            .state
+            .blocking(move |state| state.put_chunk(&key, index, &chunk))
+            .await;
+        let Ok(Ok(())) = stored else {
+            return Err(failed)};
+        self.count += 1;
+        Ok(())
+    }
+    /// Writes the rest of the paste, returning its body along with the BLAKE3 hash of its contents
+    /// and whether it was truncated
+    pub async fn finish(
+        mut self,
+    ) -> Result<(PasteBody, [u8; 32], bool), (StatusCode, &'static str)> {
+        if self.truncated {
+            let tail: Vec<u8> = self
+                .head
+                .iter()
+                .chain(&self.pending)
+                .rev()
+                .take(HOLD_BACK)
+                .rev()
+                .copied()
+                .collect();
+            let mut cut = incomplete_char(&tail);
+            self.size -= cut;
+            let from_pending = cut.min(self.pending.len());
+            self.pending.truncate(self.pending.len() - from_pending);
+            cut -= from_pending;
+            self.head.truncate(self.head.len() - cut);
+        }
+        while !self.pending.is_empty() {
+            self.flush(self.pending.len().min(self.chunk_size)).await?;
+        }
+        if !self.head_hashed {
+            self.hasher.update(&self.head);
+        }
+        self.finished = true;
+        let chunks = (self.count != 0).then(|| Chunks {
+            key: std::mem::take(&mut self.key),
+            count: self.count,
+            chunk_size: self.chunk_size as u32,
+            size: self.size as u64,
+        });
+        let body = PasteBody {
+            contents: std::mem::take(&mut self.head),
+            chunks,
+        };
+        Ok((body, *self.hasher.finalize().as_bytes(), self.truncated))
+    }
+}
+
+impl Drop for ChunkWriter<'_> {
+    fn drop(&mut self) {
+        if !self.finished && self.count != 0 {
+            let (state, key) = (self.state.clone(), std::mem::take(&mut self.key));
+            tokio::task::spawn_blocking(move || {
+                if let Err(error) = state.delete_chunks(&key) {
+                    tracing::error!(
+                        "Failed to delete the chunks of an abandoned upload: {error:?}"
+                    );
+                }
+            });
+        }
+    }
+}
+
+/// How many bytes at the end of `data` are a UTF-8 character that was cut off
+fn incomplete_char(data: &[u8]) -> usize {
+    for (back, &byte) in data.iter().rev().take(HOLD_BACK).enumerate() {
+        let width = match byte {
+            0x80..=0xbf => continue,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return 0,
+        };
+        return if width > back + 1 { back + 1 } else { 0 };
+    }
+    0
+}
+
+/// The part of a paste of `size` bytes requested in the `Range` header, `None` for all of it.
+///
+/// Headers we don't support, like ones asking for several ranges, are ignored as the RFC allows.
+pub fn requested_range(headers: &HeaderMap, size: u64) -> Result<Option<Range<u64>>, StatusCode> {
+    let Some(range) = headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.trim().strip_prefix("bytes=")) else {
+        return Ok(None)};
+    let Some((start, end)) = range.split_once('-') else {
+        return Ok(None)};
+    let range = match (start.trim().parse::<u64>(), end.trim().parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(size),
+        (Ok(start), Err(_)) if end.trim().is_empty() => start..size,
+        (Err(_), Ok(suffix)) if start.trim().is_empty() => size.saturating_sub(suffix)..size,
+        _ => return Ok(None),
+    };
+    if range.start >= range.end {
+        return Err(StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+    Ok(Some(range))
+}
+
+/// Streams `range` of a paste made of `head` and `chunks`, reading the chunks one at a time
+pub fn stream(state: CurState, head: Vec<u8>, chunks: Option<Chunks>, range: Range<u64>) -> Body {
+    let head = Bytes::from(head);
+    let head_end = head.len() as u64;
+    let head_part =
+        head.slice(range.start.min(head_end) as usize..range.end.min(head_end) as usize);
+    let Some(chunks) = chunks else {
+        return Body::from(head_part)};
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if !head_part.is_empty() && sender.send_data(head_part).await.is_err() {
+            return;
+        }
+        let first = (range.start.saturating_sub(head_end) / chunks.chunk_size as u64) as u32;
+        for index in first..chunks.count {
+            let chunk_range = chunks.range(head_end, index);
+            if chunk_range.start >= range.end {
+                break;
+            }
+            let key = chunks.key.clone();
+            let chunk = state
+                .blocking(move |state| state.get_chunk(&key, index))
+                .await;
+            let expected = chunk_range.end - chunk_range.start;
+            let chunk = match chunk {
+                Ok(Ok(Some(chunk))) if chunk.len() as u64 == expected => {
+                    Bytes::from(chunk)
+                }
+                _ => {
+                    tracing::error!("Chunk {index} of {} is missing or corrupt", chunks.key);
+                    sender.abort();
+                    return;
+                }
+            };
+            let from = range.start.saturating_sub(chunk_range.start) as usize;
+            let to = (range.end.min(chunk_range.end) - chunk_range.start) as usize;
+            // Fails once the client went away
+            if sender.send_data(chunk.slice(from..to)).await.is_err() {
+                return;
+            }
+        }
+    });
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn finds_cut_off_characters() {
+        assert_eq!(incomplete_char("aé".as_bytes()), 0);
+        assert_eq!(incomplete_char(&"aé".as_bytes()[..2]), 1);
+        assert_eq!(incomplete_char(&"€".as_bytes()[..2]), 2);
+        assert_eq!(incomplete_char(b"\xff\xfe\xfd"), 0);
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let range = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::RANGE, HeaderValue::from_str(value).unwrap());
+            requested_range(&headers, 100)
+        };
+        assert_eq!(requested_range(&HeaderMap::new(), 100), Ok(None));
+        assert_eq!(range("bytes=0-9"), Ok(Some(0..10)));
+        assert_eq!(range("bytes=90-"), Ok(Some(90..100)));
+        assert_eq!(range("bytes=-10"), Ok(Some(90..100)));
+        assert_eq!(range("bytes=50-500"), Ok(Some(50..100)));
+        assert_eq!(range("bytes=0-1,5-6"), Ok(None));
+        assert_eq!(range("bytes=100-"), Err(StatusCode::RANGE_NOT_SATISFIABLE));
+    }
+}
@@ -1,62 +1,176 @@
+use crate::crypto::{self, CryptoError};
 use clap::{Parser, Subcommand};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
+    /// Sets a custom config file
+    #[arg(short, long, value_name = "FILE", env = "OXII_CONFIG")]
+    pub config: Option<PathBuf>,
+
     /// The length of IDs to generate for pastes
-    paste_id_len: Option<usize>,
+    #[arg(long, env = "OXII_PASTE_ID_LENGTH")]
+    pub paste_id_length: Option<usize>,
 
     /// The length of IDs to generate for links
-    link_id_len: Option<usize>,
+    #[arg(long, env = "OXII_URL_ID_LENGTH")]
+    pub url_id_length: Option<usize>,
 
-    /// Sets a custom config file
-    #[arg(short, long, value_name = "ADDr")]
-    socketaddr: Option<String>,
+    /// The public URL of this instance, used when generating links
+    #[arg(short, long, value_name = "URL", env = "OXII_PUBLIC_URL")]
+    pub public_url: Option<String>,
+
+    /// The address to listen on
+    #[arg(short, long, value_name = "ADDR", env = "OXII_LISTEN")]
+    pub listen: Option<SocketAddr>,
+
+    /// The path of the RocksDB database directory
+    #[arg(long, value_name = "DIR", env = "OXII_DB_PATH")]
+    pub db_path: Option<PathBuf>,
+
+    /// The directory static files and templates are served from
+    #[arg(long, value_name = "DIR", env = "OXII_FILES_DIR")]
+    pub files_dir: Option<PathBuf>,
+
+    /// The name of the column family shortened URLs are stored in
+    #[arg(long, env = "OXII_URL_CF")]
+    pub url_cf: Option<String>,
+
+    /// The name of the column family pastes are stored in
+    #[arg(long, env = "OXII_PASTE_CF")]
+    pub paste_cf: Option<String>,
+
+    /// The name of the column family view counters are stored in
+    #[arg(long, env = "OXII_COUNTER_CF")]
+    pub counter_cf: Option<String>,
+
+    /// The name of the column family the chunks of large pastes are stored in
+    #[arg(long, env = "OXII_CHUNK_CF")]
+    pub chunk_cf: Option<String>,
+
+    /// The name of the column family paste bodies are stored in, keyed by their hash
+    #[arg(long, env = "OXII_BODY_CF")]
+    pub body_cf: Option<String>,
+
+    /// The name of the column family the earlier revisions of pastes are stored in
+    #[arg(long, env = "OXII_REVISION_CF")]
+    pub revision_cf: Option<String>,
+
+    /// The maximum size of a paste in bytes
+    #[arg(long, env = "OXII_MAX_PASTE_BYTES")]
+    pub max_paste_bytes: Option<usize>,
+
+    /// The size in bytes of the chunks large pastes are stored in
+    #[arg(long, env = "OXII_CHUNK_SIZE")]
+    pub chunk_size: Option<usize>,
+
+    /// The number of seconds entries live for when no TTL is requested, 0 to keep them forever
+    #[arg(long, value_name = "SECONDS", env = "OXII_DEFAULT_TTL")]
+    pub default_ttl: Option<u64>,
+
+    /// The longest TTL in seconds a client may request, 0 for no limit
+    #[arg(long, value_name = "SECONDS", env = "OXII_MAX_TTL")]
+    pub max_ttl: Option<u64>,
+
+    /// How often in seconds to remove expired entries from the database, 0 to never
+    #[arg(long, value_name = "SECONDS", env = "OXII_REAP_INTERVAL")]
+    pub reap_interval: Option<u64>,
+
+    /// Requests per minute a client may make to create pastes and links, 0 for no limit
+    #[arg(long, env = "OXII_RATE_LIMIT_CREATE")]
+    pub rate_limit_create: Option<u32>,
+
+    /// Requests per minute a client may make to delete pastes and links, 0 for no limit
+    #[arg(long, env = "OXII_RATE_LIMIT_DELETE")]
+    pub rate_limit_delete: Option<u32>,
+
+    /// Requests per minute a client may make to read pastes, links and pages, 0 for no limit
+    #[arg(long, env = "OXII_RATE_LIMIT_READ")]
+    pub rate_limit_read: Option<u32>,
+
+    /// Requests per minute a client may make for embed images, 0 for no limit
+    #[arg(long, env = "OXII_RATE_LIMIT_IMAGE")]
+    pub rate_limit_image: Option<u32>,
+
+    /// Wrong passwords per minute a client may try on protected pastes, 0 for no limit
+    #[arg(long, env = "OXII_RATE_LIMIT_UNLOCK")]
+    pub rate_limit_unlock: Option<u32>,
+
+    /// Identify clients by the X-Forwarded-For header, only enable this behind a proxy setting it
+    #[arg(long, value_name = "BOOL", env = "OXII_TRUST_FORWARDED_FOR")]
+    pub trust_forwarded_for: Option<bool>,
+
+    /// A directory of .tmTheme files to offer as highlighting themes
+    #[arg(long, value_name = "DIR", env = "OXII_THEMES_DIR")]
+    pub themes_dir: Option<PathBuf>,
+
+    /// A directory of .sublime-syntax files to highlight more languages with
+    #[arg(long, value_name = "DIR", env = "OXII_SYNTAXES_DIR")]
+    pub syntaxes_dir: Option<PathBuf>,
+
+    /// Where to cache the syntaxes compiled from the syntaxes directory
+    #[arg(long, value_name = "FILE", env = "OXII_SYNTAX_CACHE")]
+    pub syntax_cache: Option<PathBuf>,
+
+    /// The highlighting theme of pastes and embed images until a client picks one
+    #[arg(long, value_name = "THEME", env = "OXII_DEFAULT_THEME")]
+    pub default_theme: Option<String>,
 
     /// Turn debugging information on
     #[arg(short, long, action = clap::ArgAction::Count)]
-    debug: u8,
+    pub debug: u8,
 
     #[command(subcommand)]
-    command: Option<Commands>,
+    pub command: Option<Command>,
 }
 
+/// Helpers for terminal users, run instead of the server
 #[derive(Subcommand)]
-enum Commands {
-    /// does testing things
-    Test {
-        /// lists test values
-        #[arg(short, long)]
-        list: bool,
+pub enum Command {
+    /// Encrypts standard input into an encrypted paste on standard output, printing the key to
+    /// standard error. Upload the output with the X-Encrypted header set to 1.
+    Encrypt {
+        /// Encrypt with this key instead of a new one
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// Decrypts an encrypted paste from standard input onto standard output
+    Decrypt {
+        /// The key, or the link to the paste ending in it
+        key: String,
     },
 }
 
-fn main() {
-    let cli = Cli::parse();
-
-    // You can check the value provided by positional arguments, or option arguments
-
-    // You can see how many times a particular flag or argument occurred
-    // Note, only flags can have multiple occurrences
-    match cli.debug {
-        0 => println!("Debug mode is off"),
-        1 => println!("Debug mode is kind of on"),
-        2 => println!("Debug mode is on"),
-        _ => println!("Don't be crazy"),
-    }
-
-    // You can check for the existence of subcommands, and if found use their
-    // matches just as you would the top level cmd
-    match &cli.command {
-        Some(Commands::Test { list }) => {
-            if *list {
-                println!("Printing testing lists...");
-            } else {
-                println!("Not printing testing lists...");
+impl Command {
+    pub fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut input = Vec::new();
+        std::io::stdin().read_to_end(&mut input)?;
+        let output = match self {
+            Command::Encrypt { key } => {
+                let key = match key {
+                    Some(key) => crypto::decode_key(&key).map_err(describe)?,
+                    None => crypto::generate_key(),
+                };
+                eprintln!("{}", crypto::encode_key(&key));
+                crypto::encrypt(&key, &input)
+            }
+            Command::Decrypt { key } => {
+                let key = crypto::decode_key(&key).map_err(describe)?;
+                crypto::decrypt(&key, &input).map_err(describe)?
             }
-        }
-        None => {}
+        };
+        std::io::stdout().write_all(&output)?;
+        Ok(())
     }
+}
 
-    // Continued program logic goes here...
+fn describe(error: CryptoError) -> &'static str {
+    match error {
+        CryptoError::Format => "The input isn't an encrypted paste",
+        CryptoError::Key => "The key must be 32 bytes of URL-safe base64",
+        CryptoError::Authentication => "The key is wrong or the paste was tampered with",
+    }
 }
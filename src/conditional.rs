@@ -0,0 +1,189 @@
+//! Validators and caching headers for responses built from pastes, whose revisions never change
+//! once they are created
+
+use crate::state::Entry;
+use crate::StatusCode;
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, LocalResult, TimeZone, Utc};
+
+/// A day, the longest a response may be cached for, as the owner of a paste can still delete it
+const MAX_AGE: i64 = 60 * 60 * 24;
+
+/// How long clients may reuse a response without asking whether it changed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Caching {
+    /// The response only depends on the paste, so it stays fresh for a while unless the paste
+    /// expires before
+    Paste,
+    /// The response shows the latest revision of a paste, which its owner may replace
+    Latest,
+    /// The response also depends on the pages of the server, so it is revalidated every time
+    Page,
+}
+
+/// The validators and `Cache-Control` of a response built from a paste
+pub struct Validators {
+    /// Strong, as a paste always gives the same bytes for the same representation
+    etag: Option<String>,
+    last_modified: Option<i64>,
+    cache_control: String,
+}
+
+impl Validators {
+    /// `variant` names the representation, as the raw paste, its HTML page and its embed image
+    /// each need their own ETag. Pastes burned after reading are never cached.
+    pub fn new(entry: &Entry, variant: Option<&str>, caching: Caching) -> Self {
+        if entry.burn_after_reading {
+            return Validators {
+                etag: None,
+                last_modified: None,
+                cache_control: "no-store".to_string(),
+            };
+        }
+        let etag = entry.content_hash().map(|hash| {
+            // The bare hash of a protected paste would let anyone confirm a guess of it
+            let hash = match &entry.password_hash {
+                Some(password_hash) => blake3::Hasher::new()
+                    .update(&hash)
+                    .update(password_hash.as_bytes())
+                    .finalize(),
+                None => blake3::Hash::from(hash),
+            };
+            let mut etag = hash.to_hex().to_string();
+            if let Some(variant) = variant {
+                etag.push('-');
+                etag.extend(variant.chars().map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '.' {
+                        c
+                    } else {
+                        '_'
+                    }
+                }));
+            }
+            format!("\"{etag}\"")
+        });
+        let cache_control = match (caching, entry.expiry) {
+            // Shared caches would hand a protected paste to anyone
+            _ if entry.password_hash.is_some() => "private, no-cache".to_string(),
+            (Caching::Page | Caching::Latest, _) => "no-cache".to_string(),
+            (Caching::Paste, None) => format!("public, max-age={MAX_AGE}"),
+            (Caching::Paste, Some(expiry)) => {
+                let left = (expiry - Utc::now().timestamp()).clamp(0, MAX_AGE);
+                format!("public, max-age={left}")
+            }
+        };
+        Validators {
+            etag,
+            last_modified: Some(entry.creationdate),
+            cache_control,
+        }
+    }
+    /// Whether the copy the client has cached is still current, going by `If-None-Match` or,
+    /// when that isn't sent, `If-Modified-Since`
+    pub fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+            let (Some(etag), Ok(if_none_match)) = (&self.etag, if_none_match.to_str()) else {
+                return false};
+            return if_none_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+        }
+        let Some(last_modified) = self.last_modified else {
+            return false};
+        headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|since| since.to_str().ok())
+            .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+            .map_or(false, |since| last_modified <= since.timestamp())
+    }
+    pub fn apply(&self, response: &mut Response) {
+        let headers = response.headers_mut();
+        if let Some(Ok(etag)) = self.etag.as_deref().map(HeaderValue::from_str) {
+            headers.insert(header::ETAG, etag);
+        }
+        if let Some(last_modified) = self.last_modified.and_then(http_date) {
+            headers.insert(header::LAST_MODIFIED, last_modified);
+        }
+        if let Ok(cache_control) = HeaderValue::from_str(&self.cache_control) {
+            headers.insert(header::CACHE_CONTROL, cache_control);
+        }
+    }
+    /// The 304 sent instead of a representation the client already has
+    pub fn not_modified_response(&self) -> (StatusCode, Response) {
+        let mut response = ().into_response();
+        self.apply(&mut response);
+        (StatusCode::NOT_MODIFIED, response)
+    }
+}
+
+/// Keeps shared caches from storing a response that sets a cookie, which they would hand to
+/// everyone asking for the same URL
+pub fn make_private(response: &mut Response) {
+    let headers = response.headers_mut();
+    let cache_control = headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|cache_control| cache_control.to_str().ok())
+        .unwrap_or("no-cache");
+    let mut directives = vec!["private"];
+    directives.extend(
+        cache_control
+            .split(',')
+            .map(str::trim)
+            .filter(|directive| *directive != "public"),
+    );
+    if directives[1..].contains(&"private") || directives.contains(&"no-store") {
+        return;
+    }
+    let cache_control = directives.join(", ");
+    if let Ok(cache_control) = HeaderValue::from_str(&cache_control) {
+        headers.insert(header::CACHE_CONTROL, cache_control);
+    }
+}
+
+/// Formats a unix timestamp as an HTTP date
+fn http_date(timestamp: i64) -> Option<HeaderValue> {
+    let LocalResult::Single(date) = Utc.timestamp_opt(timestamp, 0) else {
+        return None};
+    HeaderValue::from_str(&date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revalidates_cached_copies() {
+        let mut entry = Entry::new("paste", false);
+        entry.creationdate = 784_111_777;
+        let validators = Validators::new(&entry, Some("html.rs"), Caching::Paste);
+        let etag = validators.etag.clone().unwrap();
+        assert!(etag.ends_with("-html.rs\""));
+        let request = |name, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+            validators.not_modified(&headers)
+        };
+        assert!(!validators.not_modified(&HeaderMap::new()));
+        let etags = format!("\"other\", W/{etag}");
+        assert!(request(header::IF_NONE_MATCH, &etags));
+        assert!(!request(header::IF_NONE_MATCH, "\"other\""));
+        let since = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let earlier = "Sun, 06 Nov 1994 08:49:36 GMT";
+        assert!(request(header::IF_MODIFIED_SINCE, since));
+        assert!(!request(header::IF_MODIFIED_SINCE, earlier));
+        assert_eq!(http_date(784_111_777).unwrap(), since);
+
+        let mut response = ().into_response();
+        validators.apply(&mut response);
+        make_private(&mut response);
+        let cache_control = &response.headers()[header::CACHE_CONTROL];
+        assert_eq!(cache_control, "private, max-age=86400");
+
+        entry.burn_after_reading = true;
+        let validators = Validators::new(&entry, None, Caching::Paste);
+        assert_eq!(validators.cache_control, "no-store");
+        assert!(!validators.not_modified(&HeaderMap::new()));
+    }
+}
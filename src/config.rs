@@ -0,0 +1,176 @@
+use crate::cli::Cli;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use url::Url;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    InvalidUrl(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "failed to read config file: {error}"),
+            ConfigError::Toml(error) => write!(f, "failed to parse config file: {error}"),
+            ConfigError::InvalidUrl(url) => write!(f, "`{url}` is not a valid public URL"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Runtime configuration of the server.
+///
+/// Values are resolved in order of precedence: command line flags, environment variables, the
+/// TOML config file and finally the defaults below.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub paste_id_length: usize,
+    pub url_id_length: usize,
+    pub public_url: String,
+    pub listen: SocketAddr,
+    pub db_path: PathBuf,
+    pub files_dir: PathBuf,
+    pub url_cf: String,
+    pub paste_cf: String,
+    pub counter_cf: String,
+    pub chunk_cf: String,
+    pub body_cf: String,
+    pub revision_cf: String,
+    pub max_paste_bytes: usize,
+    /// Size of the chunks the part of a paste that doesn't fit in its record is split into
+    pub chunk_size: usize,
+    /// Seconds an entry lives for when the client doesn't request a TTL, 0 for forever
+    pub default_ttl: u64,
+    /// The longest TTL in seconds a client may request, 0 for no limit
+    pub max_ttl: u64,
+    /// Seconds between sweeps of the database for expired entries, 0 to disable them
+    pub reap_interval: u64,
+    /// Requests per minute and client for creating entries, 0 for no limit
+    pub rate_limit_create: u32,
+    /// Requests per minute and client for deleting entries, 0 for no limit
+    pub rate_limit_delete: u32,
+    /// Requests per minute and client for reading entries and pages, 0 for no limit
+    pub rate_limit_read: u32,
+    /// Requests per minute and client for rendering embed images, 0 for no limit
+    pub rate_limit_image: u32,
+    /// Wrong passwords per minute and client for protected pastes, 0 for no limit
+    pub rate_limit_unlock: u32,
+    /// Whether to identify clients by `X-Forwarded-For`, only safe behind a proxy that sets it
+    pub trust_forwarded_for: bool,
+    /// A directory of `.tmTheme` files offered as highlighting themes along with the built-in ones
+    pub themes_dir: Option<PathBuf>,
+    /// A directory of `.sublime-syntax` files added to the built-in syntaxes at startup
+    pub syntaxes_dir: Option<PathBuf>,
+    /// Where the syntaxes compiled from `syntaxes_dir` are cached between restarts
+    pub syntax_cache: PathBuf,
+    /// The highlighting theme of pages and embed images when the client didn't pick one, the hand
+    /// written one if unset
+    pub default_theme: Option<String>,
+    /// The host part of `public_url`, used to refuse shortening links to ourselves
+    #[serde(skip)]
+    pub host: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            paste_id_length: 3,
+            url_id_length: 3,
+            public_url: "https://oxlink.dev".to_string(),
+            listen: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            db_path: PathBuf::from("db"),
+            files_dir: PathBuf::from("files"),
+            url_cf: "URL".to_string(),
+            paste_cf: "PASTE".to_string(),
+            counter_cf: "COUNTERS".to_string(),
+            chunk_cf: "CHUNKS".to_string(),
+            body_cf: "BODIES".to_string(),
+            revision_cf: "REVISIONS".to_string(),
+            max_paste_bytes: 1024 * 1024 * 16,
+            chunk_size: 1024 * 64,
+            default_ttl: 0,
+            max_ttl: 0,
+            reap_interval: 600,
+            rate_limit_create: 30,
+            rate_limit_delete: 30,
+            rate_limit_read: 300,
+            rate_limit_image: 30,
+            rate_limit_unlock: 5,
+            trust_forwarded_for: false,
+            themes_dir: None,
+            syntaxes_dir: None,
+            syntax_cache: PathBuf::from("syntaxes.packdump"),
+            default_theme: None,
+            host: String::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_cli(cli: Cli) -> Result<Config, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let file = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+                toml::from_str(&file).map_err(ConfigError::Toml)?
+            }
+            None => Config::default(),
+        };
+        macro_rules! apply {
+            ($($field:ident),*) => {
+                $(if let Some(value) = cli.$field {
+                    config.$field = value;
+                })*
+            };
+        }
+        apply!(
+            paste_id_length,
+            url_id_length,
+            public_url,
+            listen,
+            db_path,
+            files_dir,
+            url_cf,
+            paste_cf,
+            counter_cf,
+            chunk_cf,
+            body_cf,
+            revision_cf,
+            max_paste_bytes,
+            chunk_size,
+            default_ttl,
+            max_ttl,
+            reap_interval,
+            rate_limit_create,
+            rate_limit_delete,
+            rate_limit_read,
+            rate_limit_image,
+            rate_limit_unlock,
+            trust_forwarded_for,
+            syntax_cache
+        );
+        if cli.themes_dir.is_some() {
+            config.themes_dir = cli.themes_dir;
+        }
+        if cli.syntaxes_dir.is_some() {
+            config.syntaxes_dir = cli.syntaxes_dir;
+        }
+        if cli.default_theme.is_some() {
+            config.default_theme = cli.default_theme;
+        }
+        config.public_url = config.public_url.trim_end_matches('/').to_string();
+        let Some(host) = Url::parse(&config.public_url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string)) else {
+            return Err(ConfigError::InvalidUrl(config.public_url))
+        };
+        config.host = host;
+        config.chunk_size = config.chunk_size.max(1);
+        Ok(config)
+    }
+}
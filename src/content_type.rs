@@ -0,0 +1,114 @@
+//! Working out the MIME type of pastes, and serving them without letting uploads run scripts on
+//! our origin
+
+/// Types clients send when they don't know what they are uploading
+const GENERIC: [&str; 3] = [
+    "application/octet-stream",
+    "application/x-www-form-urlencoded",
+    "multipart/form-data",
+];
+
+/// Types browsers display without being able to run scripts in them
+const INLINE: [&str; 8] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/avif",
+    "image/bmp",
+    "application/pdf",
+    "text/plain",
+];
+
+/// Non `text/*` types that are served as plain text
+const TEXT: [&str; 6] = [
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/toml",
+    "application/x-sh",
+    "application/x-yaml",
+];
+
+/// Works out the MIME type of uploaded data: the type the client declared if it is specific,
+/// otherwise the one its magic bytes match, falling back to plain text for UTF-8 and binary for
+/// anything else. `data` can be just the start of a paste.
+pub fn detect(declared: Option<&str>, data: &[u8]) -> String {
+    let declared = declared
+        .and_then(|declared| declared.split(';').next())
+        .map(|declared| declared.trim().to_ascii_lowercase())
+        .filter(|declared| valid(declared) && !GENERIC.contains(&declared.as_str()));
+    if let Some(declared) = declared {
+        return declared;
+    }
+    if let Some(kind) = infer::get(data) {
+        return kind.mime_type().to_string();
+    }
+    match std::str::from_utf8(data) {
+        // The start of a large paste can end in the middle of a character
+        Err(error) if error.error_len().is_some() => "application/octet-stream".to_string(),
+        _ => "text/plain".to_string(),
+    }
+}
+
+/// Whether `content_type` is a bare `type/subtype`, with nothing that could break out of a header
+/// or an HTML attribute
+fn valid(content_type: &str) -> bool {
+    let Some((kind, subtype)) = content_type.split_once('/') else {
+        return false};
+    let token = |part: &str| {
+        !part.is_empty()
+            && part
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || b"!#$^_.+-".contains(&c))
+    };
+    token(kind) && token(subtype)
+}
+
+pub fn is_text(content_type: &str) -> bool {
+    content_type.starts_with("text/") || TEXT.contains(&content_type)
+}
+
+/// Images that are previewed on the HTML page of a paste
+pub fn is_image(content_type: &str) -> bool {
+    content_type.starts_with("image/") && INLINE.contains(&content_type)
+}
+
+/// The type a paste is served with and whether browsers may display it instead of downloading it.
+/// Text is always served as `text/plain`, so uploaded HTML or SVG is never rendered.
+pub fn serve_as(content_type: &str) -> (&str, bool) {
+    if is_text(content_type) {
+        ("text/plain; charset=utf-8", true)
+    } else {
+        (content_type, INLINE.contains(&content_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_types() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(detect(None, png), "image/png");
+        assert_eq!(detect(Some("application/octet-stream"), png), "image/png");
+        assert_eq!(
+            detect(Some("Text/X-Rust; charset=utf-8"), b"fn"),
+            "text/x-rust"
+        );
+        assert_eq!(
+            detect(Some("text/html\r\nX-Evil: 1"), b"hello"),
+            "text/plain"
+        );
+        assert_eq!(detect(None, b"\x80\x81\x82"), "application/octet-stream");
+        assert_eq!(detect(None, &"aé".as_bytes()[..2]), "text/plain");
+    }
+
+    #[test]
+    fn never_serves_markup() {
+        assert_eq!(serve_as("text/html").0, "text/plain; charset=utf-8");
+        assert_eq!(serve_as("image/svg+xml"), ("image/svg+xml", false));
+        assert_eq!(serve_as("image/png"), ("image/png", true));
+    }
+}
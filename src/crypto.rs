@@ -0,0 +1,108 @@
+//! The format of end-to-end encrypted pastes. Clients encrypt pastes before uploading them and
+//! keep the key in the fragment of the link, which browsers never send, so the server only ever
+//! stores ciphertext. `files/paster.js` implements the same format with WebCrypto.
+//!
+//! A paste is `OXE`, the format version, a random 12 byte nonce and the AES-256-GCM ciphertext of
+//! the contents followed by its tag. The first four bytes are authenticated as associated data.
+//! Keys are 32 random bytes, written in unpadded URL-safe base64.
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::Rng;
+
+const MAGIC: &[u8; 3] = b"OXE";
+const VERSION: u8 = 1;
+const HEADER: [u8; 4] = [MAGIC[0], MAGIC[1], MAGIC[2], VERSION];
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CryptoError {
+    /// The data isn't an encrypted paste, or was written by a newer version
+    Format,
+    /// The key isn't 32 bytes of base64
+    Key,
+    /// The key is wrong or the paste was tampered with
+    Authentication,
+}
+
+pub fn generate_key() -> [u8; 32] {
+    rand::thread_rng().gen()
+}
+
+pub fn encode_key(key: &[u8; 32]) -> String {
+    URL_SAFE_NO_PAD.encode(key)
+}
+
+/// Decodes a key, or the key at the end of a link to an encrypted paste
+pub fn decode_key(key: &str) -> Result<[u8; 32], CryptoError> {
+    let key = key.rsplit(['#', '!']).next().unwrap_or(key).trim();
+    let Ok(key) = URL_SAFE_NO_PAD.decode(key) else {
+        return Err(CryptoError::Key)};
+    key.try_into().map_err(|_| CryptoError::Key)
+}
+
+/// Whether `data` looks like an encrypted paste, which is all the server can check
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(&HEADER) && data.len() >= HEADER.len() + NONCE_LENGTH + TAG_LENGTH
+}
+
+pub fn encrypt(key: &[u8; 32], contents: &[u8]) -> Vec<u8> {
+    let nonce: [u8; NONCE_LENGTH] = rand::thread_rng().gen();
+    let payload = Payload {
+        msg: contents,
+        aad: &HEADER,
+    };
+    let ciphertext = Aes256Gcm::new(key.into())
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .expect("Encrypting in memory can't fail");
+    let mut encrypted = Vec::with_capacity(HEADER.len() + NONCE_LENGTH + ciphertext.len());
+    encrypted.extend_from_slice(&HEADER);
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&ciphertext);
+    encrypted
+}
+
+pub fn decrypt(key: &[u8; 32], encrypted: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if !is_encrypted(encrypted) {
+        return Err(CryptoError::Format);
+    }
+    let (nonce, ciphertext) = encrypted[HEADER.len()..].split_at(NONCE_LENGTH);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: &HEADER,
+    };
+    Aes256Gcm::new(key.into())
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| CryptoError::Authentication)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let key = generate_key();
+        let encrypted = encrypt(&key, b"secret paste");
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), b"secret paste");
+        assert_eq!(
+            decrypt(&generate_key(), &encrypted),
+            Err(CryptoError::Authentication)
+        );
+        assert_eq!(decrypt(&key, b"secret paste"), Err(CryptoError::Format));
+    }
+
+    #[test]
+    fn decodes_keys_from_links() {
+        let key = generate_key();
+        let encoded = encode_key(&key);
+        assert_eq!(decode_key(&encoded), Ok(key));
+        let link = format!("https://example.com/#abc.rs!{encoded}");
+        assert_eq!(decode_key(&link), Ok(key));
+        assert_eq!(decode_key("too-short"), Err(CryptoError::Key));
+    }
+}
@@ -1,117 +1,631 @@
 use std::io::Cursor;
 
-use axum::body::Bytes;
-use axum::extract::State;
-use axum::http::{header, HeaderMap, HeaderValue};
-use axum::response::{Html, IntoResponse};
+use axum::body::{Body, HttpBody};
+use axum::extract::multipart::Field;
+use axum::extract::{Form, FromRequest, Multipart, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, Request};
+use axum::response::{Html, IntoResponse, Response};
+use axum::Extension;
 use chrono::Utc;
 use image::{ImageFormat, Rgba};
 use imageproc::drawing::{draw_line_segment_mut, draw_text_mut};
 use lazy_static::lazy_static;
 use rusttype::{Font, Scale};
+use serde::Deserialize;
 use syntect::easy::HighlightLines;
 use syntect::highlighting::FontStyle;
 use syntect::util::LinesWithEndings;
 
+use crate::api::{created_response, Created, Info};
 use crate::bot::isbot;
-use crate::state::{CurState, Entry};
-use crate::syntax::highlight_to_html;
-use crate::util::{new_embed, SYNTAXSET, THEME};
+use crate::chunks::{self, ChunkWriter, Chunks};
+use crate::conditional::{make_private, Caching, Validators};
+use crate::content_type;
+use crate::crypto;
+use crate::language;
+use crate::lines::LineRange;
+use crate::password::{self, basic_auth, password_required, Credentials, PASSWORD_HEADER};
+use crate::ratelimit::ClientIp;
+use crate::state::{CurState, Entry, PasteBody};
+use crate::syntax::{highlight_to_html, BUILTIN_THEME, THEME_COOKIE};
+use crate::util::{
+    expiry_for, flag, new_embed, request_token, sanitize_filename, sanitize_html, CreateOptions,
+    TokenQuery, SIZE, SYNTAXSET,
+};
 use crate::ClientType;
-use crate::{id, StatusCode, UrlPath, IP, MAX_PASTE_BYTES, PASTE_CF, PASTE_ID_LENGTH};
+use crate::{id, StatusCode, UrlPath};
 use chrono::TimeZone;
 
 pub async fn new_paste(
     State(state): State<CurState>,
-    mut data: Bytes,
-) -> Result<(StatusCode, String), (StatusCode, &'static str)> {
-    let length = data.len();
-    if length == 0 {
+    headers: HeaderMap,
+    Query(mut options): Query<CreateOptions>,
+    request: Request<Body>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let config = &state.config;
+    let (uploads, truncated) = uploaded_files(&state, &mut options, request).await?;
+    let expiry = match expiry_for(&headers, &options, config) {
+        Ok(expiry) => expiry,
+        Err(error) => {
+            for upload in &uploads {
+                discard_chunks(&state, upload.body.chunks.as_ref());
+            }
+            return Err(error);
+        }
+    };
+    let mut entries = Vec::new();
+    let mut uploads = uploads.into_iter();
+    while let Some(upload) = uploads.next() {
+        match paste_entry(&state, &headers, &options, expiry, upload).await {
+            Ok(entry) => entries.push(entry),
+            Err(error) => {
+                for (entry, _) in &entries {
+                    discard(&state, entry);
+                }
+                for upload in uploads {
+                    discard_chunks(&state, upload.body.chunks.as_ref());
+                }
+                return Err(error);
+            }
+        }
+    }
+    let inserted = state
+        .blocking(move |state| {
+            let (cf, id_length) = (&state.config.paste_cf, state.config.paste_id_length);
+            let mut ids = Vec::new();
+            for (index, (entry, _)) in entries.iter().enumerate() {
+                let Ok(id) = state.insert_new(entry, cf, id_length) else {
+                    // The files of an upload are stored all or none
+                    for id in &ids {
+                        if let Err(error) = state.delete(id, cf) {
+                            tracing::error!(
+                                "Failed to delete a paste of a failed upload: {error:?}"
+                            );
+                        }
+                    }
+                    for (entry, _) in &entries[index..] {
+                        discard(state, entry);
+                    }
+                    return None;
+                };
+                ids.push(id);
+            }
+            Some((ids, entries))
+        })
+        .await;
+    let Ok(Some((ids, entries))) = inserted else {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Malformed response from the database",
+        ));
+    };
+    let created: Vec<Created> = entries
+        .iter()
+        .zip(&ids)
+        .map(|((entry, token), id)| Created::paste(config, id, token, entry, truncated))
+        .collect();
+    Ok(created_response(
+        created_status(truncated),
+        &headers,
+        &created,
+    ))
+}
+
+/// A file uploaded to be pasted
+struct Upload {
+    /// Sanitized with `sanitize_filename`
+    filename: Option<String>,
+    /// The type the client declared, if any
+    content_type: Option<String>,
+    body: PasteBody,
+    /// BLAKE3 hash of the contents
+    hash: [u8; 32],
+}
+
+/// Receives the files uploaded in a request, storing large ones in chunks as they arrive. A
+/// `multipart/form-data` body can hold several files, any other body is a single file, which is
+/// truncated at the size limit if the client asked for it. Also returns whether it was.
+///
+/// Only the fields of a form with a file name are files, the others set `options` like the query
+/// string does, so a plain HTML form can set them too.
+async fn uploaded_files(
+    state: &CurState,
+    options: &mut CreateOptions,
+    request: Request<Body>,
+) -> Result<(Vec<Upload>, bool), (StatusCode, &'static str)> {
+    let headers = request.headers();
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(str::to_string);
+    if !content_type.as_deref().map_or(false, |content_type| {
+        content_type.starts_with("multipart/form-data")
+    }) {
+        // Ciphertext that was cut short can't be decrypted, so it is rejected instead
+        let truncate = flag(headers, "x-truncate", options.truncate.as_deref())
+            && !flag(headers, "x-encrypted", options.encrypted.as_deref());
+        let mut writer = ChunkWriter::new(state, truncate);
+        let mut body = request.into_body();
+        while let Some(data) = body.data().await {
+            let data =
+                data.map_err(|_| (StatusCode::BAD_REQUEST, "Failed to read the request body"))?;
+            if !writer.write(&data).await? {
+                break;
+            }
+        }
+        let (body, hash, truncated) = writer.finish().await?;
+        let upload = Upload {
+            filename: None,
+            content_type,
+            body,
+            hash,
+        };
+        return Ok((vec![upload], truncated));
+    }
+    let malformed = (
+        StatusCode::BAD_REQUEST,
+        "Malformed multipart/form-data body",
+    );
+    let mut multipart = Multipart::from_request(request, &())
+        .await
+        .map_err(|_| malformed)?;
+    let mut files = Vec::new();
+    // The files of an upload count towards the size limit together
+    let mut room = state.config.max_paste_bytes;
+    let received: Result<(), (StatusCode, &'static str)> = async {
+        while let Some(mut field) = multipart.next_field().await.map_err(|_| malformed)? {
+            let Some(filename) = field.file_name().map(str::to_string) else {
+                let option = match field.name().unwrap_or_default() {
+                    "ttl" => &mut options.ttl,
+                    "burn" => &mut options.burn,
+                    "encrypted" => &mut options.encrypted,
+                    "password" => &mut options.password,
+                    _ => {
+                        return Err((
+                            StatusCode::BAD_REQUEST,
+                            "Unknown form field, files must be sent with a file name",
+                        ))
+                    }
+                };
+                *option = Some(form_value(&mut field).await?);
+                continue;
+            };
+            let filename = sanitize_filename(&filename);
+            let content_type = field.content_type().map(str::to_string);
+            let mut writer = ChunkWriter::new(state, false).with_limit(room);
+            while let Some(data) = field.chunk().await.map_err(|error| {
+                if error.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                    (error.status(), "Upload is larger than the size limit")
+                } else {
+                    malformed
+                }
+            })? {
+                writer.write(&data).await?;
+            }
+            let (body, hash, _) = writer.finish().await?;
+            room -= body.size() as usize;
+            if !body.contents.is_empty() {
+                files.push(Upload {
+                    filename,
+                    content_type,
+                    body,
+                    hash,
+                });
+            }
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(error) = received {
+        for file in &files {
+            discard_chunks(state, file.body.chunks.as_ref());
+        }
+        return Err(error);
+    }
+    if files.is_empty() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Cannot create paste without any files",
+        ));
+    }
+    Ok((files, false))
+}
+
+/// The longest value accepted in a form field that isn't a file
+const MAX_FORM_VALUE: usize = 1024;
+
+/// Reads the value of a form field that isn't a file
+async fn form_value(field: &mut Field<'_>) -> Result<String, (StatusCode, &'static str)> {
+    let malformed = (
+        StatusCode::BAD_REQUEST,
+        "Malformed multipart/form-data body",
+    );
+    let mut value = Vec::new();
+    while let Some(data) = field.chunk().await.map_err(|_| malformed)? {
+        value.extend_from_slice(&data);
+        if value.len() > MAX_FORM_VALUE {
+            return Err((StatusCode::BAD_REQUEST, "Form field is too long"));
+        }
+    }
+    String::from_utf8(value).map_err(|_| (StatusCode::BAD_REQUEST, "Form fields must be UTF-8"))
+}
+
+/// Deletes the chunks uploaded for an entry that won't be stored after all. Its body is only
+/// referenced once the entry is written, so there is nothing else to undo.
+fn discard(state: &CurState, entry: &Entry) {
+    discard_chunks(state, entry.chunks.as_ref());
+}
+
+/// Deletes the chunks of an upload that won't be stored after all, on a blocking thread like
+/// `ChunkWriter` does for abandoned uploads
+fn discard_chunks(state: &CurState, chunks: Option<&Chunks>) {
+    if let Some(chunks) = chunks {
+        let (state, key) = (state.clone(), chunks.key.clone());
+        tokio::task::spawn_blocking(move || {
+            if let Err(error) = state.delete_chunks(&key) {
+                tracing::error!("Failed to delete the chunks of a discarded paste: {error:?}");
+            }
+        });
+    }
+}
+
+/// 201, or 206 if the paste was truncated as the client allowed
+fn created_status(truncated: bool) -> StatusCode {
+    if truncated {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::CREATED
+    }
+}
+
+/// Builds the entry for a new paste from an upload, along with its delete token. Nothing is stored
+/// here, the body is stored or referenced along with the entry when it is inserted. The uploaded
+/// chunks are deleted if this fails.
+async fn paste_entry(
+    state: &CurState,
+    headers: &HeaderMap,
+    options: &CreateOptions,
+    expiry: Option<i64>,
+    upload: Upload,
+) -> Result<(Entry, String), (StatusCode, &'static str)> {
+    if upload.body.contents.is_empty() {
         return Err((
             StatusCode::UNPROCESSABLE_ENTITY,
             "Cannot create paste with an empty body",
         ));
     }
-    data.truncate(MAX_PASTE_BYTES);
-    let id = id::Id::new(PASTE_ID_LENGTH).into_inner();
-    let Ok(_) = state.put(&id, Entry::new(data, 0, 0, false), PASTE_CF) else {
+    let encrypted = flag(headers, "x-encrypted", options.encrypted.as_deref());
+    if encrypted && !crypto::is_encrypted(&upload.body.contents) {
+        discard_chunks(state, upload.body.chunks.as_ref());
         return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Malformed response from the database",
+            StatusCode::BAD_REQUEST,
+            "Encrypted pastes must be encrypted in the OXE format",
         ));
-    };
-    Ok((
-        if length <= MAX_PASTE_BYTES {
-            StatusCode::CREATED
-        } else {
-            StatusCode::PARTIAL_CONTENT
+    }
+    let password = headers
+        .get(PASSWORD_HEADER)
+        .and_then(|password| password.to_str().ok())
+        .or(options.password.as_deref())
+        .filter(|password| !password.is_empty());
+    let password_hash = match password {
+        None => None,
+        Some(password) => match password::hash_blocking(password).await {
+            Some(hash) => Some(hash),
+            None => {
+                discard_chunks(state, upload.body.chunks.as_ref());
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to hash the password",
+                ));
+            }
         },
-        format!("{IP}/{}", unsafe { std::str::from_utf8_unchecked(&id) }),
-    ))
+    };
+    let token = id::Id::token();
+    let burn = flag(headers, "x-burn-after-reading", options.burn.as_deref());
+    let language = if encrypted {
+        None
+    } else {
+        language::detect(upload.filename.as_deref(), &upload.body.contents)
+    };
+    let mut entry = Entry::new(upload.body.contents, encrypted)
+        .with_delete_token(&token)
+        .with_expiry(expiry)
+        .with_burn_after_reading(burn)
+        .with_content_type(upload.content_type.as_deref())
+        .with_filename(upload.filename)
+        .with_language(language)
+        .with_chunks(upload.body.chunks)
+        .with_hash(Some(upload.hash))
+        .with_password_hash(password_hash);
+    if encrypted {
+        // The type of ciphertext means nothing, and the declared one could give the contents away
+        entry.content_type = "application/octet-stream".to_string();
+    }
+    Ok((entry, token))
+}
+
+#[derive(Deserialize)]
+pub struct ViewQuery {
+    theme: Option<String>,
+    lines: Option<String>,
 }
 
 pub async fn get_paste(
     UrlPath(paste): UrlPath<String>,
     headers: HeaderMap,
+    Query(query): Query<ViewQuery>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
     State(state): State<CurState>,
-) -> Result<(StatusCode, impl IntoResponse), StatusCode> {
+) -> Result<(StatusCode, Response), StatusCode> {
+    let client = ClientType::from(&headers);
+    let password = basic_auth(&headers);
+    let credentials = Credentials {
+        ip,
+        password: password.as_deref(),
+    };
+    let theme = state.themes.pick(query.theme.as_deref(), &headers);
+    let lines = LineRange::from_query(query.lines.as_deref())?;
+    let (status, mut response) =
+        read_paste(&paste, &headers, &state, client, credentials, theme, lines).await?;
+    // A theme picked once is kept for the pastes viewed after it
+    if let Some(requested) = query.theme.filter(|theme| state.themes.contains(theme)) {
+        let cookie = format!("{THEME_COOKIE}={requested}; Path=/; Max-Age=31536000; SameSite=Lax");
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            response.headers_mut().append(header::SET_COOKIE, cookie);
+            make_private(&mut response);
+        }
+    }
+    Ok((status, response))
+}
+
+#[derive(Deserialize)]
+pub struct PasswordForm {
+    password: String,
+}
+
+/// Shows a protected paste to a browser that sent its password from the form
+pub async fn unlock_paste(
+    UrlPath(paste): UrlPath<String>,
+    headers: HeaderMap,
+    Query(query): Query<ViewQuery>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    State(state): State<CurState>,
+    Form(form): Form<PasswordForm>,
+) -> Result<(StatusCode, Response), StatusCode> {
+    let client = ClientType::from(&headers);
+    let credentials = Credentials {
+        ip,
+        password: Some(&form.password),
+    };
+    let theme = state.themes.pick(None, &headers);
+    let lines = LineRange::from_query(query.lines.as_deref())?;
+    read_paste(&paste, &headers, &state, client, credentials, theme, lines).await
+}
+
+/// Serves a paste as plain text, whatever the client
+pub async fn raw_paste(
+    UrlPath(paste): UrlPath<String>,
+    headers: HeaderMap,
+    Query(query): Query<ViewQuery>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    State(state): State<CurState>,
+) -> Result<(StatusCode, Response), StatusCode> {
+    let password = basic_auth(&headers);
+    let credentials = Credentials {
+        ip,
+        password: password.as_deref(),
+    };
+    let lines = LineRange::from_query(query.lines.as_deref())?;
+    let client = ClientType::NoHtml;
+    read_paste(&paste, &headers, &state, client, credentials, None, lines).await
+}
+
+/// Splits the revision number off an ID like `paste@2`
+pub fn split_revision(paste: &str) -> Result<(&str, Option<u32>), StatusCode> {
+    let Some((paste, revision)) = paste.split_once('@') else {
+        return Ok((paste, None))};
+    match revision.parse() {
+        Ok(revision) => Ok((paste, Some(revision))),
+        Err(_) => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Gets a paste, or one of its revisions if a number is given
+pub async fn get_entry(
+    state: &CurState,
+    paste: &str,
+    revision: Option<u32>,
+) -> Result<Option<Entry>, StatusCode> {
+    let paste = paste.to_owned();
+    let entry = state
+        .blocking(move |state| {
+            let cf_name = &state.config.paste_cf;
+            match revision {
+                Some(revision) => state.get_revision(&paste, revision, cf_name),
+                None => state.get(&paste, cf_name),
+            }
+        })
+        .await??;
+    Ok(entry)
+}
+
+async fn read_paste(
+    paste: &str,
+    headers: &HeaderMap,
+    state: &CurState,
+    mut client: ClientType,
+    credentials: Credentials<'_>,
+    theme: Option<&str>,
+    lines: Option<LineRange>,
+) -> Result<(StatusCode, Response), StatusCode> {
     use ClientType::*;
     let (paste, ext) = match paste.split_once('.') {
         Some((paste, ext)) => (paste, Some(ext)),
-        None => (paste.as_str(), None),
+        None => (paste, None),
     };
-    let client = ClientType::from(&headers);
+    let (paste, revision) = split_revision(paste)?;
     // no file extension
-    let Some(entry) = state.get(paste, PASTE_CF) else {
+    let Some(mut entry) = get_entry(state, paste, revision).await? else {
         return Err(StatusCode::NOT_FOUND)};
-    let (mut views, mut scrapes, data) = (entry.views, entry.scrapes, entry.contents);
-    if isbot(&headers) {
-        scrapes += 1
+    if entry.is_expired() {
+        return Err(StatusCode::GONE);
+    }
+    let bot = isbot(headers);
+    let unlocked = match password::unlock(state, &entry, credentials).await {
+        Ok(unlocked) => unlocked,
+        Err(limited) => return Ok((StatusCode::TOO_MANY_REQUESTS, limited)),
+    };
+    if !unlocked {
+        if !bot || client == Json {
+            let wrong = credentials.password.is_some();
+            return Ok(password_required(client == HTML, wrong));
+        }
+        // Link previews only show that the paste is protected
+        client = UnknownBot;
+    }
+    let size = entry.size();
+    let large = entry.chunks.is_some();
+    if client == Json {
+        // Only metadata is sent, so this neither counts as a view nor burns the paste
+        let key = paste.to_owned();
+        let counters = state
+            .blocking(move |state| state.counters(key, &state.config.paste_cf))
+            .await??;
+        let info = Info::paste(&state.config, paste, &entry, counters);
+        return Ok((StatusCode::OK, axum::Json(info).into_response()));
+    }
+    // Lines can't be told apart in ciphertext, and a slice would burn the whole paste
+    if lines.is_some() && (entry.encrypted || entry.burn_after_reading) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    // Only a revision stays the same for good, the owner can replace the latest one
+    let fixed = if revision.is_some() || !entry.has_owner() {
+        Caching::Paste
     } else {
-        views += 1
+        Caching::Latest
+    };
+    // Every kind of client gets its own representation of the paste
+    let (variant, caching) = match client {
+        NoHtml => (None, fixed),
+        // Pages link the stylesheet of the theme they are shown in
+        HTML => (
+            Some(format!("html.{}", theme.unwrap_or(BUILTIN_THEME))),
+            Caching::Page,
+        ),
+        _ => (Some("embed".to_string()), Caching::Page),
+    };
+    let variant = variant.map(|variant| format!("{variant}.{}", ext.unwrap_or_default()));
+    // Each slice is cached apart from the whole paste
+    let variant = match lines {
+        Some(range) => Some(format!(
+            "{}.lines{range}",
+            variant.as_deref().unwrap_or("raw")
+        )),
+        None => variant,
+    };
+    let validators = Validators::new(&entry, variant.as_deref(), caching);
+    let cacheable = |(status, mut response): (StatusCode, Response)| {
+        validators.apply(&mut response);
+        response.headers_mut().insert(
+            header::VARY,
+            HeaderValue::from_static("Accept, User-Agent, Cookie"),
+        );
+        (status, response)
+    };
+    // A client that already has the paste neither views nor burns it again
+    if validators.not_modified(headers) {
+        return Ok(validators.not_modified_response());
     }
-    state
-        .put(
-            paste,
-            Entry::new(data.clone(), views, scrapes, false),
-            PASTE_CF,
-        )
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sliced = read_lines(state, &entry, lines).await?;
+    if entry.burn_after_reading {
+        if bot {
+            // Bots only ever get the embed, so link previews don't burn the paste
+            client = UnknownBot;
+        } else {
+            // Another reader might have burned the paste since it was fetched
+            let key = paste.to_owned();
+            let burned = state
+                .blocking(move |state| state.take(key, &state.config.paste_cf))
+                .await??;
+            let Some(burned) = burned else {
+                return Err(StatusCode::NOT_FOUND)};
+            entry.contents = burned.contents;
+            entry.chunks = None;
+        }
+    }
+    if !entry.burn_after_reading {
+        let key = paste.to_owned();
+        state
+            .blocking(move |state| state.count_view(key, &state.config.paste_cf, bot))
+            .await??;
+    }
+    let filename = entry.filename.clone();
+    let content_type = entry.content_type.clone();
+    // Without an extension in the URL, the language detected when the paste was created picks the
+    // syntax, or the extension of the uploaded file for pastes from before languages were detected
+    let uploaded_ext = entry
+        .extension()
+        .filter(|_| entry.language.is_none())
+        .map(str::to_string);
+    let ext = ext.or(uploaded_ext.as_deref());
+    let language = entry.language.take();
+    let (data, chunks) = (entry.contents, entry.chunks);
+    let burned = entry.burn_after_reading;
+    let raw = |data: Vec<u8>| {
+        // A slice is sent whole on its own, without the chunks of the paste it was cut from
+        let (data, chunks) = match &sliced {
+            Some(slice) => (slice.clone(), None),
+            None => (data, chunks.clone()),
+        };
+        cacheable(raw_response(
+            state,
+            headers,
+            filename.as_deref(),
+            &content_type,
+            data,
+            chunks,
+            burned,
+        ))
+    };
     let out = match client {
+        // Only clients holding the key can show an encrypted paste
+        HTML if entry.encrypted => Ok(raw(data)),
+        // Large pastes are too slow to highlight, so they are offered as a download
+        HTML if large
+            || !content_type::is_text(&content_type)
+                && (content_type::is_image(&content_type)
+                    || std::str::from_utf8(&data).is_err()) =>
+        {
+            // The page would link to a paste that no longer exists, or show the whole paste
+            if entry.burn_after_reading || lines.is_some() {
+                return Ok(raw(data));
+            }
+            let page = file_page(paste, filename.as_deref(), &content_type, size);
+            Ok((StatusCode::OK, page.into_response()))
+        }
         HTML => {
-            let Some(ext) = ext else {
-                return Ok((
-                    StatusCode::OK,
-                    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], data).into_response(),
-                ))};
             let Ok(text) = std::str::from_utf8(&data) else {
                 // If data isn't valid UTF-8, return it as plain text without syntax highlighting
-                return Ok((
-                    StatusCode::OK,
-                    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], data).into_response(),
-                ))};
+                return Ok(raw(data))};
             // If data is valid UTF-8, return with syntax highlighting
-            let Some(syntax) = SYNTAXSET.find_syntax_by_token(ext) else {
-                // If data isn't valid UTF-8, return it as plain text without syntax highlighting
-                return Ok((
-                    StatusCode::OK,
-                    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], data).into_response(),
-                ))};
-            let data = highlight_to_html(
-                text,
-                &SYNTAXSET,
-                syntax,
+            let Some(syntax) = language::syntax_for(ext, language.as_deref()) else {
+                // Without a known language, return it as plain text without syntax highlighting
+                return Ok(raw(data))};
+            let chrome = format!(
                 "
 <div class=\"box\">
-				<button onclick=\"window.location.href = window.location.href.slice(0, window.location.href.lastIndexOf('/'))\">New Paste</button>
-				<button onclick=\"let loc = window.location.href;window.location.href = loc.slice(0,loc.lastIndexOf('/')) + loc.slice(loc.lastIndexOf('/')).replace('/','#');\">Copy &amp; Edit</button>
-				<button onclick=\"let loc = window.location.href;window.location.href = loc.slice(0,loc.lastIndexOf('/')) + '/a' + loc.slice(loc.lastIndexOf('/'));\">Analytics</button>
+				<button onclick=\"window.location.href = window.location.origin\">New Paste</button>
+				<button onclick=\"let loc = window.location.origin + window.location.pathname;window.location.href = loc.slice(0,loc.lastIndexOf('/')) + loc.slice(loc.lastIndexOf('/')).replace('/','#');\">Copy &amp; Edit</button>
+				<button onclick=\"let loc = window.location.origin + window.location.pathname;window.location.href = loc.slice(0,loc.lastIndexOf('/')) + '/a' + loc.slice(loc.lastIndexOf('/'));\">Analytics</button>
+				{}
 			</div>
 			<div id=\"box_hint\" style=\"display: none;\">
 				<div class=\"label\">Save</div>
 				<div class=\"shortcut\">control + s</div>
 			</div>",
+                state.themes.picker(theme, lines)
             );
+            let data = highlight_to_html(text, &SYNTAXSET, syntax, theme, &chrome, lines);
             Ok((StatusCode::OK, Html(data).into_response()))
 
             //             let data = r"<!DOCTYPE html>
@@ -135,12 +649,9 @@ pub async fn get_paste(
             //                 + r"
             // </code></pre></body></html>";
         }
-        NoHtml => Ok((
-            StatusCode::OK,
-            ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], data).into_response(),
-        )),
+        NoHtml => Ok(raw(data)),
         _ => {
-            let url = format!("{IP}/{paste}{}", {
+            let url = format!("{}/{paste}{}", state.config.public_url, {
                 if let Some(ext) = ext {
                     format!(".{ext}")
                 } else {
@@ -151,7 +662,13 @@ pub async fn get_paste(
             //     .replace("'", "");
             // let words = data.get(..35.min(data.len())).unwrap();
             // let mut title = String::with_capacity(64);
-            let title = "Paste on OxiiLink";
+            let title = if entry.password_hash.is_some() {
+                "Protected paste on OxiiLink"
+            } else if entry.encrypted {
+                "Encrypted paste on OxiiLink"
+            } else {
+                "Paste on OxiiLink"
+            };
             // let mut title = words
             //     .split_whitespace()
             //     .rev()
@@ -170,7 +687,7 @@ pub async fn get_paste(
                     240,
                     &format!(
                         "{}/i/{paste}{}",
-                        IP,
+                        state.config.public_url,
                         ext.map(|x| format!(".{x}")).unwrap_or_default()
                     ),
                 )
@@ -178,22 +695,159 @@ pub async fn get_paste(
             ))
         }
     };
-    out
+    out.map(cacheable)
+}
+
+/// The lines of a paste asked for in `?lines=`. Large pastes are only read up to the chunk the
+/// range ends in.
+async fn read_lines(
+    state: &CurState,
+    entry: &Entry,
+    lines: Option<LineRange>,
+) -> Result<Option<Vec<u8>>, StatusCode> {
+    let Some(range) = lines else {
+        return Ok(None)};
+    let (head, chunks) = (entry.contents.clone(), entry.chunks.clone());
+    let slice = state
+        .blocking(move |state| state.read_lines(&head, chunks.as_ref(), range))
+        .await??;
+    slice.ok_or(StatusCode::RANGE_NOT_SATISFIABLE).map(Some)
+}
+
+/// The contents of a paste, streamed so browsers can't run scripts from it and named after the
+/// file it was uploaded from if any. Only the part requested in a `Range` header is sent, unless
+/// the paste was `burned`, see `sent_range`.
+fn raw_response(
+    state: &CurState,
+    headers: &HeaderMap,
+    filename: Option<&str>,
+    content_type: &str,
+    data: Vec<u8>,
+    chunks: Option<Chunks>,
+    burned: bool,
+) -> (StatusCode, Response) {
+    let size = chunks
+        .as_ref()
+        .map_or(data.len() as u64, |chunks| chunks.size);
+    let (status, range) = match sent_range(headers, size, burned) {
+        Ok(Some(range)) => (StatusCode::PARTIAL_CONTENT, range),
+        Ok(None) => (StatusCode::OK, 0..size),
+        Err(status) => {
+            let content_range = [(header::CONTENT_RANGE, format!("bytes */{size}"))];
+            return (status, content_range.into_response());
+        }
+    };
+    let (content_type, inline) = content_type::serve_as(content_type);
+    let mut disposition = if inline { "inline" } else { "attachment" }.to_string();
+    if let Some(filename) = filename {
+        disposition += &format!("; filename=\"{filename}\"");
+    }
+    let mut response = (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            (header::CONTENT_SECURITY_POLICY, "sandbox"),
+            (header::ACCEPT_RANGES, if burned { "none" } else { "bytes" }),
+        ],
+        chunks::stream(state.clone(), data, chunks, range.clone()),
+    )
+        .into_response();
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from(range.end - range.start),
+    );
+    if status == StatusCode::PARTIAL_CONTENT {
+        let content_range = format!("bytes {}-{}/{size}", range.start, range.end - 1);
+        if let Ok(content_range) = HeaderValue::from_str(&content_range) {
+            response_headers.insert(header::CONTENT_RANGE, content_range);
+        }
+    }
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        response_headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    (status, response)
+}
+
+/// The part of a paste of `size` bytes to send, `None` for all of it. A paste burned after reading
+/// is always sent whole, as it is already deleted and the rest of it couldn't be requested later.
+fn sent_range(
+    headers: &HeaderMap,
+    size: u64,
+    burned: bool,
+) -> Result<Option<std::ops::Range<u64>>, StatusCode> {
+    if burned {
+        return Ok(None);
+    }
+    chunks::requested_range(headers, size)
+}
+
+/// The HTML page of a paste that isn't text or is too large to display, offering a download.
+/// Images are previewed from the raw paste, which the browser fetches and caches on its own.
+fn file_page(paste: &str, filename: Option<&str>, content_type: &str, size: u64) -> Html<String> {
+    let escape = |text: &str| sanitize_html(text).replace('\'', "&#39;");
+    let (paste, name) = (escape(paste), escape(filename.unwrap_or(paste)));
+    let preview = if content_type::is_image(content_type) {
+        format!("<img src='/r/{paste}' alt='{name}' style='max-width: 100%' /><br />")
+    } else {
+        String::new()
+    };
+    Html(format!(
+        "<html><head>
+<meta charset='utf-8'>
+<meta name='author' content='CordlessCoder'>
+<title>OxiiLink - {name}</title>
+<link rel='stylesheet' href='/files/style.css'>
+</head><body>
+{preview}<p>{name} ({content_type}, {} bytes)</p>
+<a href='/r/{paste}' download='{name}'><button>Download</button></a>
+</body></html>",
+        size
+    ))
 }
 
 pub async fn delete_paste(
     UrlPath(paste): UrlPath<String>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
     State(state): State<CurState>,
 ) -> (StatusCode, &'static str) {
+    let id = paste
+        .split_once('.')
+        .map(|(name, _)| name)
+        .unwrap_or(&paste);
+    if id.contains('@') {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Revisions can only be deleted along with their paste.",
+        );
+    }
+    let key = id.to_owned();
+    let entry = state
+        .blocking(move |state| state.get(key, &state.config.paste_cf))
+        .await;
+    let entry = match entry {
+        Ok(Ok(Some(entry))) => entry,
+        Ok(Ok(None)) => return (StatusCode::NOT_FOUND, "Paste not found."),
+        _ => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Malformed response from database.",
+            )
+        }
+    };
+    let Some(token) = request_token(&headers, &query) else {
+        return (StatusCode::FORBIDDEN, "A delete token is required to delete this paste.")};
+    if !entry.check_delete_token(token) {
+        return (StatusCode::FORBIDDEN, "Invalid delete token.");
+    }
     state.cache.remove(&paste).await;
-    match state.delete(
-        paste
-            .split_once('.')
-            .map(|(name, _)| name)
-            .unwrap_or(&paste),
-        PASTE_CF,
-    ) {
-        Ok(_) => (StatusCode::OK, "Success"),
+    let id = id.to_owned();
+    let deleted = state
+        .blocking(move |state| state.delete(id, &state.config.paste_cf))
+        .await;
+    match deleted {
+        Ok(Ok(_)) => (StatusCode::OK, "Success"),
         _ => (
             StatusCode::INTERNAL_SERVER_ERROR,
             "Malformed response from database.",
@@ -201,32 +855,160 @@ pub async fn delete_paste(
     }
 }
 
+/// Creates a paste under an ID chosen by the client, or a new revision of it if the ID is taken
+/// and the request carries its delete token
 pub async fn create_paste(
     UrlPath(paste): UrlPath<String>,
-    data: String,
     State(state): State<CurState>,
-) -> Result<(StatusCode, String), (StatusCode, &'static str)> {
-    let length = paste.len();
-    if length > 16 || length <= 1 {
-        return Err((StatusCode::BAD_REQUEST, "custom ID out of bounds"));
+    headers: HeaderMap,
+    Query(mut options): Query<CreateOptions>,
+    Query(query): Query<TokenQuery>,
+    request: Request<Body>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    id::validate_custom(&paste).map_err(|error| (StatusCode::BAD_REQUEST, error))?;
+    let config = &state.config;
+    let conflict = (StatusCode::CONFLICT, "Paste with this name already exists");
+    // Saves receiving the paste when the ID is obviously taken, it is checked again when storing
+    let key = paste.clone();
+    let taken = state
+        .blocking(move |state| state.key_exists(key, &state.config.paste_cf))
+        .await;
+    if let Ok(Ok(true)) = taken {
+        let Some(token) = request_token(&headers, &query) else {
+            return Err(conflict)};
+        return update_paste(&state, &paste, token, &headers, &mut options, request).await;
+    }
+    let (upload, truncated) = single_upload(&state, &mut options, request).await?;
+    let expiry = match expiry_for(&headers, &options, config) {
+        Ok(expiry) => expiry,
+        Err(error) => {
+            discard_chunks(&state, upload.body.chunks.as_ref());
+            return Err(error);
+        }
+    };
+    let (entry, token) = paste_entry(&state, &headers, &options, expiry, upload).await?;
+    let key = paste.clone();
+    let inserted = state
+        .blocking(move |state| {
+            let inserted = state.insert_if_absent(&key, &entry, &state.config.paste_cf);
+            if !matches!(inserted, Ok(true)) {
+                discard(state, &entry);
+            }
+            inserted.map(|inserted| inserted.then_some(entry))
+        })
+        .await;
+    let entry = match inserted {
+        Ok(Ok(Some(entry))) => entry,
+        Ok(Ok(None)) => return Err(conflict),
+        _ => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Malformed response from the database",
+            ))
+        }
+    };
+    Ok(created_response(
+        created_status(truncated),
+        &headers,
+        &[Created::paste(config, &paste, &token, &entry, truncated)],
+    ))
+}
+
+/// Receives the one file a paste under a custom ID is made of, and whether it was truncated
+async fn single_upload(
+    state: &CurState,
+    options: &mut CreateOptions,
+    request: Request<Body>,
+) -> Result<(Upload, bool), (StatusCode, &'static str)> {
+    let (mut files, truncated) = uploaded_files(state, options, request).await?;
+    if files.len() > 1 {
+        for file in &files {
+            discard_chunks(state, file.body.chunks.as_ref());
+        }
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Only one file can be uploaded under a custom ID",
+        ));
     }
-    let Ok(exists) = state.key_exists(&paste, PASTE_CF) else {
+    Ok((files.remove(0), truncated))
+}
+
+/// Replaces the contents of a paste with a new revision, keeping the earlier ones. The paste
+/// keeps its delete token and expiry, and its password unless a new one is set.
+async fn update_paste(
+    state: &CurState,
+    paste: &str,
+    token: &str,
+    headers: &HeaderMap,
+    options: &mut CreateOptions,
+    request: Request<Body>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let config = &state.config;
+    let not_found = (StatusCode::NOT_FOUND, "Paste not found.");
+    let key = paste.to_owned();
+    let current = state
+        .blocking(move |state| state.get(key, &state.config.paste_cf))
+        .await;
+    let current = match current {
+        Ok(Ok(Some(entry))) if !entry.is_expired() => entry,
+        Ok(Ok(_)) => return Err(not_found),
+        _ => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Malformed response from the database",
+            ))
+        }
+    };
+    if !current.check_delete_token(token) {
+        return Err((StatusCode::FORBIDDEN, "Invalid delete token."));
+    }
+    if current.burn_after_reading {
         return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Malformed response from the database",
-        ))};
-    if exists {
-        Err((StatusCode::CONFLICT, "Paste with this name already exists"))
-    } else {
-        let Some(data_trunacted) = data.get(0..(MAX_PASTE_BYTES.min(data.len()))) else {
-            return Err((StatusCode::UNPROCESSABLE_ENTITY, "Incorrect request body"))};
-        let Ok(_) = state.put(&paste, Entry::new(data_trunacted, 0, 0, false), PASTE_CF) else {
+            StatusCode::CONFLICT,
+            "Pastes burned after reading can't be edited",
+        ));
+    }
+    let (upload, truncated) = single_upload(state, options, request).await?;
+    let (mut entry, _) = paste_entry(state, headers, options, current.expiry, upload).await?;
+    entry.delete_hash = current.delete_hash;
+    entry.burn_after_reading = false;
+    if entry.password_hash.is_none() {
+        entry.password_hash = current.password_hash;
+    }
+    let key = paste.to_owned();
+    let revised = state
+        .blocking(move |state| {
+            let revision = state.put_revision(&key, &mut entry, &state.config.paste_cf);
+            if !matches!(revision, Ok(Some(_))) {
+                discard(state, &entry);
+            }
+            revision.map(|revision| revision.map(|revision| (revision, entry)))
+        })
+        .await;
+    let (revision, entry) = match revised {
+        Ok(Ok(Some(revised))) => revised,
+        Ok(Ok(None)) => return Err(not_found),
+        _ => {
             return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Malformed response from the database",
-            ));
-        };
-        Ok((StatusCode::CREATED, format!("{IP}/p/{}", &paste)))
+            ))
+        }
+    };
+    let mut created = Created::paste(config, paste, token, &entry, truncated);
+    created.revision = Some(revision);
+    Ok(created_response(StatusCode::OK, headers, &[created]))
+}
+
+/// The text in the start of a paste, which is cut off in the middle of a character if the paste
+/// is stored in chunks
+fn text_start(data: &[u8]) -> Option<&str> {
+    match std::str::from_utf8(data) {
+        Ok(text) => Some(text),
+        Err(error) if error.error_len().is_none() => {
+            std::str::from_utf8(&data[..error.valid_up_to()]).ok()
+        }
+        Err(_) => None,
     }
 }
 
@@ -241,11 +1023,12 @@ lazy_static! {
 
 pub const BACKGROUND: Rgba<u8> = Rgba([17, 18, 29, 255]);
 pub const FOREGROUND: Rgba<u8> = Rgba([247, 118, 142, 255]);
-pub const SIZE: (i32, i32) = (1200, 600);
 
 pub async fn paste_image(
     UrlPath(pasteurl): UrlPath<String>,
-    // headers: HeaderMap,
+    headers: HeaderMap,
+    Query(query): Query<ViewQuery>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
     State(state): State<CurState>,
 ) -> Result<(StatusCode, impl IntoResponse), StatusCode> {
     // use ClientType::*;
@@ -257,40 +1040,107 @@ pub async fn paste_image(
         Some((paste, ext)) => (paste, Some(ext)),
         None => (pasteurl.as_str(), None),
     };
+    let (paste, revision) = split_revision(paste)?;
     // no file extension
 
-    if paste.as_bytes().len() > PASTE_ID_LENGTH {
-        return Err(StatusCode::NOT_FOUND);
-    }
-    let Some((data, created_at)) = state.get(paste, PASTE_CF).map(|x|(x.contents, x.creationdate)) else {
+    let Some(entry) = get_entry(&state, paste, revision).await? else {
         return Err(StatusCode::NOT_FOUND)};
-    let data = if let Ok(data) = std::str::from_utf8(&data) {
+    if entry.is_expired() {
+        return Err(StatusCode::GONE);
+    }
+    let lines = LineRange::from_query(query.lines.as_deref())?;
+    // Link previews of a protected paste get a card that doesn't show it
+    let password = basic_auth(&headers);
+    let credentials = Credentials {
+        ip,
+        password: password.as_deref(),
+    };
+    let unlocked = match password::unlock(&state, &entry, credentials).await {
+        Ok(unlocked) => unlocked,
+        Err(limited) => return Ok((StatusCode::TOO_MANY_REQUESTS, limited)),
+    };
+    if !unlocked && !isbot(&headers) {
+        return Ok(password_required(false, headers.contains_key(header::AUTHORIZATION)));
+    }
+    let size = entry.size();
+    let created_at = entry.creationdate;
+    // Only the lines asked for are drawn, numbered as they are in the paste
+    let lines = lines.filter(|_| unlocked && !entry.burn_after_reading && !entry.encrypted);
+    let sliced = read_lines(&state, &entry, lines).await?;
+    let shown = sliced.as_deref().unwrap_or(&entry.contents);
+    let first_line = lines.map_or(1, |range| range.start);
+    let description;
+    let data = if !unlocked {
+        "This paste is protected by a password."
+    } else if entry.burn_after_reading {
+        "This paste will be deleted after it is read."
+    } else if entry.encrypted {
+        "This paste is encrypted."
+    } else if let Some(data) = text_start(shown) {
         data
     } else {
-        "Binary paste"
+        description = format!(
+            "{}\n{}, {} bytes",
+            entry.filename.as_deref().unwrap_or("Binary paste"),
+            entry.content_type,
+            size
+        );
+        &description
     };
-    let syntax = if let Some(Some(syntax)) = ext.map(|ext| SYNTAXSET.find_syntax_by_token(ext)) {
+    // The language of a locked paste would tell something about it
+    let language = entry.language.as_deref().filter(|_| unlocked);
+    let syntax = if let Some(syntax) = language::syntax_for(ext, language) {
         syntax
     } else {
         SYNTAXSET
             .find_syntax_by_first_line(data)
             .unwrap_or(SYNTAXSET.find_syntax_plain_text())
     };
-    if let Some(cached) = state.cache.get(&format!("{paste}{}", syntax.name)) {
+    // Cookies aren't sent by the bots fetching the image, so only the query picks a theme
+    let theme = state.themes.resolve(query.theme.as_deref());
+    let (highlighting, palette) = state.themes.for_image(theme);
+    let theme_slug = theme.unwrap_or(BUILTIN_THEME);
+    let mut variant = if unlocked {
+        format!("png.{theme_slug}.{}", syntax.name)
+    } else {
+        format!("png.locked.{theme_slug}.{}", syntax.name)
+    };
+    let range = lines
+        .map(|range| format!(".lines{range}"))
+        .unwrap_or_default();
+    variant.push_str(&range);
+    let caching = if revision.is_some() || !entry.has_owner() {
+        Caching::Paste
+    } else {
+        Caching::Latest
+    };
+    let validators = Validators::new(&entry, Some(&variant), caching);
+    if validators.not_modified(&headers) {
+        return Ok(validators.not_modified_response());
+    }
+    // Keyed by the contents too, so a new revision of the paste gets a new image
+    let hash = entry
+        .content_hash()
+        .filter(|_| unlocked)
+        .map(|hash| blake3::Hash::from(hash).to_hex().to_string())
+        .unwrap_or_default();
+    let cache_key = format!("{paste}{hash}{theme_slug}{}{range}", syntax.name);
+    if let Some(cached) = state.cache.get(&cache_key) {
         let mut response = cached.value().clone().into_response();
         let _ = response
             .headers_mut()
             .insert("Content-type", HeaderValue::from_static("image/png"));
+        validators.apply(&mut response);
         return Ok((StatusCode::OK, response));
     }
 
     let size = SIZE;
     let padding = 5;
-    let mut image = *state.image.clone();
+    let mut image = (*state.card(theme)).clone();
 
     draw_text_mut(
         &mut image,
-        Rgba([169, 177, 214, 255]),
+        palette.text,
         padding as i32,
         padding as i32,
         Scale { x: 50.0, y: 50.0 },
@@ -299,7 +1149,7 @@ pub async fn paste_image(
     );
     draw_text_mut(
         &mut image,
-        Rgba([169, 177, 214, 255]),
+        palette.text,
         padding as i32,
         padding as i32 + 45,
         Scale { x: 30.0, y: 30.0 },
@@ -311,12 +1161,13 @@ pub async fn paste_image(
                 .format("%H:%M %d/%m/%Y")
         ),
     );
-    let gutter = 48;
+    // Wide enough for the numbers of the lines that fit on the card
+    let gutter = 48.max(23 * (first_line + 10).to_string().len() as i32 + 2);
     let mut cursor = Cursor::new(Vec::with_capacity(image.len()));
     {
         // Scope for working with HighlightLines, for some reason everything breaks if
         // HighlightLines is in the main scope
-        let mut h = HighlightLines::new(syntax, &THEME);
+        let mut h = HighlightLines::new(syntax, highlighting);
         let mut lines = LinesWithEndings::from(data)
             .filter_map(|line| h.highlight_line(line, &SYNTAXSET).ok())
             .enumerate()
@@ -336,18 +1187,18 @@ pub async fn paste_image(
             // Draw line number
             draw_text_mut(
                 &mut image,
-                FOREGROUND,
+                palette.line_number,
                 padding,
                 y as i32,
                 scale,
                 &FONT,
-                &(nr + 1).to_string(),
+                &(nr + first_line).to_string(),
             );
             draw_line_segment_mut(
                 &mut image,
                 (0.0, y + scale.y),
                 (gutter as f32, y + scale.y),
-                Rgba([65, 72, 104, 255]),
+                palette.rule,
             );
             for (style, word) in line {
                 let chars_left = ((size.0 - (x as i32 + padding)) as f32 / char_width) as usize;
@@ -439,16 +1290,26 @@ pub async fn paste_image(
     let image = cursor.into_inner();
     state
         .cache
-        .insert(
-            format!("{paste}{}", syntax.name),
-            image.clone(),
-            image.len() as i64,
-        )
+        .insert(cache_key, image.clone(), image.len() as i64)
         .await;
 
     let mut response = image.into_response();
     let _ = response
         .headers_mut()
         .insert("Content-type", HeaderValue::from_static("image/png"));
+    validators.apply(&mut response);
     Ok((StatusCode::OK, response))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burned_pastes_are_sent_whole() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_static("bytes=0-9"));
+        assert_eq!(sent_range(&headers, 100, false), Ok(Some(0..10)));
+        assert_eq!(sent_range(&headers, 100, true), Ok(None));
+    }
+}
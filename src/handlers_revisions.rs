@@ -0,0 +1,145 @@
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::response::{Html, IntoResponse, Response};
+use axum::{Extension, Json};
+use serde::Deserialize;
+use similar::TextDiff;
+
+use crate::api::Revision;
+use crate::password::{self, basic_auth, password_required, Credentials};
+use crate::ratelimit::ClientIp;
+use crate::state::{CurState, DBFailure, Entry};
+use crate::syntax::highlight_to_html;
+use crate::util::{ClientType, SYNTAXSET};
+use crate::{StatusCode, UrlPath};
+
+/// Lists the revisions of a paste, oldest first
+pub async fn list_revisions(
+    UrlPath(paste): UrlPath<String>,
+    headers: HeaderMap,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    State(state): State<CurState>,
+) -> Result<Response, StatusCode> {
+    let key = paste.clone();
+    let entries = state
+        .blocking(move |state| -> Result<_, DBFailure> {
+            let cf_name = &state.config.paste_cf;
+            let latest = state.latest_revision(&key, cf_name)?;
+            let mut entries: Vec<(u32, Entry)> = Vec::with_capacity(latest as usize);
+            for revision in 1..=latest {
+                if let Some(entry) = state.get_revision(&key, revision, cf_name)? {
+                    entries.push((revision, entry));
+                }
+            }
+            Ok(entries)
+        })
+        .await??;
+    let Some((_, latest)) = entries.last() else {
+        return Err(StatusCode::NOT_FOUND)};
+    if latest.is_expired() {
+        return Err(StatusCode::GONE);
+    }
+    let password = basic_auth(&headers);
+    let credentials = Credentials {
+        ip,
+        password: password.as_deref(),
+    };
+    match password::unlock(&state, latest, credentials).await {
+        Ok(true) => (),
+        Ok(false) => return Ok(password_required(false, password.is_some()).into_response()),
+        Err(limited) => return Ok(limited),
+    }
+    let revisions: Vec<Revision> = entries
+        .iter()
+        .map(|(revision, entry)| Revision::new(&state.config, &paste, *revision, entry))
+        .collect();
+    if ClientType::from(&headers) == ClientType::Json {
+        return Ok(Json(revisions).into_response());
+    }
+    let lines: Vec<String> = revisions
+        .iter()
+        .map(|revision| {
+            format!(
+                "{}\t{}\t{} bytes\t{}",
+                revision.revision, revision.created, revision.size, revision.url
+            )
+        })
+        .collect();
+    Ok(lines.join("\n").into_response())
+}
+
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    from: Option<u32>,
+    to: Option<u32>,
+    theme: Option<String>,
+}
+
+/// Shows a unified diff between two revisions of a paste, by default between the latest one and
+/// the one before it. Browsers get it highlighted, other clients as plain text.
+pub async fn diff_paste(
+    UrlPath(paste): UrlPath<String>,
+    headers: HeaderMap,
+    Query(query): Query<DiffQuery>,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    State(state): State<CurState>,
+) -> Result<Response, StatusCode> {
+    let (key, from, to) = (paste.clone(), query.from, query.to);
+    let revisions = state
+        .blocking(move |state| -> Result<_, DBFailure> {
+            let cf_name = &state.config.paste_cf;
+            let to = match to {
+                Some(to) => to,
+                None => state.latest_revision(&key, cf_name)?,
+            };
+            let from = from.unwrap_or(to.saturating_sub(1).max(1));
+            let old = state.get_revision(&key, from, cf_name)?;
+            let new = state.get_revision(&key, to, cf_name)?;
+            Ok((from, to, old, new))
+        })
+        .await??;
+    let (from, to, old, new) = revisions;
+    let (Some(old), Some(new)) = (old, new) else {
+        return Err(StatusCode::NOT_FOUND)};
+    if new.is_expired() {
+        return Err(StatusCode::GONE);
+    }
+    // Reading the paste here would show it without burning it
+    if new.burn_after_reading {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let password = basic_auth(&headers);
+    let credentials = Credentials {
+        ip,
+        password: password.as_deref(),
+    };
+    for entry in [&old, &new] {
+        match password::unlock(&state, entry, credentials).await {
+            Ok(true) => (),
+            Ok(false) => return Ok(password_required(false, password.is_some()).into_response()),
+            Err(limited) => return Ok(limited),
+        }
+    }
+    // Large pastes are too slow to diff, like they are to highlight, and ciphertext can't be
+    if old.chunks.is_some() || new.chunks.is_some() || old.encrypted || new.encrypted {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let old_text = std::str::from_utf8(&old.contents);
+    let new_text = std::str::from_utf8(&new.contents);
+    let (Ok(old_text), Ok(new_text)) = (old_text, new_text) else {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY)};
+    let diff = TextDiff::from_lines(old_text, new_text)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("{paste}@{from}"), &format!("{paste}@{to}"))
+        .to_string();
+    if ClientType::from(&headers) != ClientType::HTML {
+        return Ok(diff.into_response());
+    }
+    let syntax = SYNTAXSET
+        .find_syntax_by_token("diff")
+        .unwrap_or_else(|| SYNTAXSET.find_syntax_plain_text());
+    let theme = state.themes.pick(query.theme.as_deref(), &headers);
+    let html = highlight_to_html(&diff, &SYNTAXSET, syntax, theme, "", None);
+    Ok(Html(html).into_response())
+}
@@ -1,135 +1,172 @@
 use crate::{
+    api::{created_response, Created, Info},
     bot::isbot,
     id,
     state::{CurState, Entry},
-    Redirect, StatusCode, Url, UrlPath, IP, URL_CF, URL_ID_LENGTH,
+    util::{expiry_for, request_token, ClientType, CreateOptions, TokenQuery},
+    Redirect, StatusCode, Url, UrlPath,
+};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
 };
-use axum::{extract::State, http::HeaderMap, response::IntoResponse};
-use lazy_static::lazy_static;
 
 pub async fn get_url(
     headers: HeaderMap,
     UrlPath(short): UrlPath<String>,
     State(state): State<CurState>,
-) -> Result<Redirect, StatusCode> {
-    let key = short.as_bytes();
-    let Some(entry) = state.get(key, URL_CF) else {
+) -> Result<Response, StatusCode> {
+    let key = short.clone();
+    let entry = state
+        .blocking(move |state| state.get(key, &state.config.url_cf))
+        .await??;
+    let Some(entry) = entry else {
         return Err(StatusCode::NOT_FOUND)};
-    let (mut views, mut scrapes, contents) = (entry.views, entry.scrapes, entry.contents);
-    if isbot(&headers) {
-        scrapes += 1
-    } else {
-        views += 1
+    if entry.is_expired() {
+        return Err(StatusCode::GONE);
+    }
+    if ClientType::from(&headers) == ClientType::Json {
+        // Looking up a link through the API doesn't follow it, so it isn't counted as a view
+        let key = short.clone();
+        let counters = state
+            .blocking(move |state| state.counters(key, &state.config.url_cf))
+            .await??;
+        return Ok(Json(Info::url(&state.config, &short, &entry, counters)).into_response());
     }
+    let (key, bot) = (short, isbot(&headers));
     state
-        .put(
-            key,
-            Entry::new(contents.clone(), views, scrapes, false),
-            URL_CF,
-        )
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(Redirect::to(unsafe {
-        std::str::from_utf8_unchecked(&contents)
-    }))
+        .blocking(move |state| state.count_view(key, &state.config.url_cf, bot))
+        .await??;
+    Ok(Redirect::to(unsafe { std::str::from_utf8_unchecked(&entry.contents) }).into_response())
 }
 
 pub async fn delete_url(
     UrlPath(short): UrlPath<String>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
     State(state): State<CurState>,
 ) -> StatusCode {
-    match state.delete(short, URL_CF) {
-        Ok(_) => StatusCode::OK,
+    let key = short.clone();
+    let entry = state
+        .blocking(move |state| state.get(key, &state.config.url_cf))
+        .await;
+    let entry = match entry {
+        Ok(Ok(Some(entry))) => entry,
+        Ok(Ok(None)) => return StatusCode::NOT_FOUND,
+        _ => return StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let Some(token) = request_token(&headers, &query) else {
+        return StatusCode::FORBIDDEN};
+    if !entry.check_delete_token(token) {
+        return StatusCode::FORBIDDEN;
+    }
+    let deleted = state
+        .blocking(move |state| state.delete(short, &state.config.url_cf))
+        .await;
+    match deleted {
+        Ok(Ok(_)) => StatusCode::OK,
         _ => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
 
+/// Creates a short link under an ID chosen by the client
 pub async fn create_url(
     UrlPath(short): UrlPath<String>,
-    mut url: String,
     State(state): State<CurState>,
-) -> Result<(StatusCode, String), (StatusCode, &'static str)> {
-    url.truncate(2048);
-    let length = short.len();
-    if length > 16 || length <= 1 {
-        return Err((StatusCode::BAD_REQUEST, "custom ID length out of bounds"));
-    }
-    let Ok(exists) = state.key_exists(&short, URL_CF) else {
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Malformed response from database",
-        ))};
-    if exists {
-        Err((
-            StatusCode::NOT_MODIFIED,
-            "A shortened URL with this ID already exists",
-        ))
-    } else {
-        let parsed_url = Url::parse(&url).map_err(|_err| {
-            (
-                StatusCode::UNPROCESSABLE_ENTITY,
-                "Does this look like a URL to you?",
-            )
-        })?;
-        let scheme = parsed_url.scheme();
-        if parsed_url.username() != "" || scheme != "http" && scheme != "https" {
+    headers: HeaderMap,
+    Query(options): Query<CreateOptions>,
+    url: String,
+) -> Result<Response, (StatusCode, &'static str)> {
+    id::validate_custom(&short).map_err(|error| (StatusCode::BAD_REQUEST, error))?;
+    let (entry, token) = url_entry(&state, &headers, &options, url)?;
+    let key = short.clone();
+    let inserted = state
+        .blocking(move |state| {
+            let inserted = state.insert_if_absent(&key, &entry, &state.config.url_cf);
+            inserted.map(|inserted| inserted.then_some(entry))
+        })
+        .await;
+    let entry = match inserted {
+        Ok(Ok(Some(entry))) => entry,
+        Ok(Ok(None)) => {
+            return Err((
+                StatusCode::CONFLICT,
+                "A shortened URL with this ID already exists",
+            ))
+        }
+        _ => {
             return Err((
-                StatusCode::UNSUPPORTED_MEDIA_TYPE,
-                "Cannot shorten this URL",
-            ));
-        };
-        match state.put(
-            &short,
-            Entry::new(parsed_url.to_string(), 0, 0, false),
-            URL_CF,
-        ) {
-            Ok(_) => Ok((StatusCode::OK, format!("{IP}/{short}\n"))),
-            Err(_) => Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Malformed response from database",
-            )),
+            ))
         }
-    }
+    };
+    Ok(created_response(
+        StatusCode::CREATED,
+        &headers,
+        &[Created::url(&state.config, &short, &token, &entry)],
+    ))
 }
 
-pub async fn shorten_url(State(state): State<CurState>, mut url: String) -> impl IntoResponse {
+pub async fn shorten_url(
+    State(state): State<CurState>,
+    headers: HeaderMap,
+    Query(options): Query<CreateOptions>,
+    url: String,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let (entry, token) = url_entry(&state, &headers, &options, url)?;
+    let created = state
+        .blocking(move |state| {
+            let config = &state.config;
+            let id = state.insert_new(&entry, &config.url_cf, config.url_id_length);
+            id.map(|id| (id, entry))
+        })
+        .await;
+    let Ok(Ok((id, entry))) = created else {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Malformed response from database",
+        ));
+    };
+    Ok(created_response(
+        StatusCode::CREATED,
+        &headers,
+        &[Created::url(&state.config, &id, &token, &entry)],
+    ))
+}
+
+/// Validates the URL to shorten and builds the entry for it, along with its delete token
+fn url_entry(
+    state: &CurState,
+    headers: &HeaderMap,
+    options: &CreateOptions,
+    mut url: String,
+) -> Result<(Entry, String), (StatusCode, &'static str)> {
     url.truncate(2048);
     let Ok(parsed_url) = Url::parse(&url) else {
-        return (
+        return Err((
             StatusCode::UNPROCESSABLE_ENTITY,
             "Does this look like a URL to you?",
-        ).into_response()
+        ));
     };
     let scheme = parsed_url.scheme();
     if parsed_url.username() != ""
         || scheme != "http" && scheme != "https"
         || parsed_url.host_str().is_none()
-        || parsed_url.host_str().unwrap() == IP_HOST.as_str()
+        || parsed_url.host_str().unwrap() == state.config.host
     {
-        return (
+        return Err((
             StatusCode::UNSUPPORTED_MEDIA_TYPE,
             "Cannot shorten this URL",
-        )
-            .into_response();
+        ));
     }
 
-    let id = id::Id::new(URL_ID_LENGTH).into_inner();
-    let Ok(_) = state
-        .put(&id, Entry::new(parsed_url.to_string(), 0, 0, false), URL_CF) else {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Malformed response from database",
-            ).into_response()
-        };
-    (
-        StatusCode::CREATED,
-        format!("{IP}/s/{}", unsafe {
-            std::str::from_utf8_unchecked(&id) // unsafe used here as the id has to be correct UTF-8 as
-                                               // we just generated it
-        }),
-    )
-        .into_response()
-}
-
-lazy_static! {
-    pub static ref IP_HOST: String = Url::parse(IP).unwrap().host_str().unwrap().to_string();
+    let expiry = expiry_for(headers, options, &state.config)?;
+    let token = id::Id::token();
+    let entry = Entry::new(parsed_url.to_string(), false)
+        .with_delete_token(&token)
+        .with_expiry(expiry);
+    Ok((entry, token))
 }
@@ -3,8 +3,58 @@ type Random = rngs::ThreadRng;
 
 pub struct Id(Vec<u8>);
 
+/// The length of the secret tokens handed out to the creator of an entry
+pub const TOKEN_LENGTH: usize = 24;
+
 const BASE62: [u8; 62] = *b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
 
+/// The bounds on the length of a custom ID
+pub const CUSTOM_LENGTH: std::ops::RangeInclusive<usize> = 2..=32;
+
+/// IDs that would shadow, or be shadowed by, one of the server's own routes
+const RESERVED: [&str; 15] = [
+    "a",
+    "api",
+    "count",
+    "d",
+    "files",
+    "help",
+    "i",
+    "languages",
+    "nothing",
+    "p",
+    "r",
+    "s",
+    "status",
+    "theme",
+    "v",
+];
+
+/// Whether `id` collides with one of the server's own routes
+pub fn is_reserved(id: &str) -> bool {
+    RESERVED
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(id))
+}
+
+/// Checks that a custom ID requested by a client is usable in a URL and doesn't collide with a
+/// route
+pub fn validate_custom(id: &str) -> Result<(), &'static str> {
+    if !CUSTOM_LENGTH.contains(&id.len()) {
+        return Err("Custom IDs must be between 2 and 32 characters long");
+    }
+    if !id
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+    {
+        return Err("Custom IDs may only contain letters, digits, '-' and '_'");
+    }
+    if is_reserved(id) {
+        return Err("This ID is reserved");
+    }
+    Ok(())
+}
+
 impl Id {
     pub fn new(length: usize) -> Id {
         let mut buf = Vec::with_capacity(length);
@@ -17,7 +67,30 @@ impl Id {
     pub fn into_inner(self) -> Vec<u8> {
         self.0
     }
+    pub fn into_string(self) -> String {
+        // The ID only ever contains ASCII characters from BASE62
+        unsafe { String::from_utf8_unchecked(self.0) }
+    }
+    /// Generates a new secret token, used to authorize changes to an entry
+    pub fn token() -> String {
+        Id::new(TOKEN_LENGTH).into_string()
+    }
     pub fn possible_ids(length: usize) -> usize {
-        BASE62.len().pow(length as u32)
+        BASE62.len().saturating_pow(length as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_ids() {
+        assert!(validate_custom("my-paste_2").is_ok());
+        assert!(validate_custom("x").is_err());
+        assert!(validate_custom("paste.rs").is_err());
+        assert!(validate_custom("../etc").is_err());
+        assert!(validate_custom("Help").is_err());
+        assert!(validate_custom(&"a".repeat(33)).is_err());
     }
 }
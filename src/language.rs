@@ -0,0 +1,318 @@
+//! Working out the language of pastes uploaded without an extension, which is most of the ones
+//! sent with `curl`. The name of the syntax found is stored with the paste when it is created, and
+//! an extension in the URL still picks another one.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use syntect::parsing::SyntaxReference;
+
+use crate::util::SYNTAXSET;
+
+/// How much of the start of a paste is looked at
+const SAMPLE: usize = 16 * 1024;
+/// Lines from the start and end of a paste that can hold a modeline
+const MODELINE_LINES: usize = 5;
+/// The score a language needs in `by_content` to be picked
+const MIN_SCORE: u32 = 6;
+
+/// Interpreters and editor modes whose name isn't an extension or syntax name themselves
+const ALIASES: [(&str, &str); 16] = [
+    ("ash", "sh"),
+    ("dash", "sh"),
+    ("ksh", "sh"),
+    ("zsh", "sh"),
+    ("node", "js"),
+    ("nodejs", "js"),
+    ("deno", "ts"),
+    ("bun", "js"),
+    ("pypy", "py"),
+    ("rscript", "r"),
+    ("runghc", "hs"),
+    ("runhaskell", "hs"),
+    ("tclsh", "tcl"),
+    ("gawk", "awk"),
+    ("make", "Makefile"),
+    ("shell-script", "sh"),
+];
+
+/// Snippets typical of a language, by one of its extensions, with how strongly each one points
+/// to it. Each snippet counts at most three times.
+const RULES: [(&str, &[(&str, u32)]); 12] = [
+    (
+        "rs",
+        &[
+            ("fn ", 2),
+            ("let mut ", 3),
+            ("impl ", 2),
+            ("pub fn ", 3),
+            ("use std::", 4),
+            ("#[derive(", 4),
+            ("&mut ", 3),
+            ("println!(", 4),
+            ("::", 1),
+        ],
+    ),
+    (
+        "py",
+        &[
+            ("def ", 2),
+            ("elif ", 4),
+            ("self.", 2),
+            ("__init__", 4),
+            ("import ", 1),
+            ("from ", 1),
+            ("print(", 1),
+            ("None", 1),
+        ],
+    ),
+    (
+        "js",
+        &[
+            ("function ", 2),
+            ("const ", 1),
+            ("=> ", 1),
+            ("console.log(", 4),
+            ("require(", 3),
+            ("document.", 3),
+            ("===", 3),
+        ],
+    ),
+    (
+        "c",
+        &[
+            ("#include <", 4),
+            ("int main(", 3),
+            ("printf(", 2),
+            ("malloc(", 3),
+            ("NULL", 1),
+        ],
+    ),
+    (
+        "go",
+        &[
+            ("package ", 3),
+            ("func ", 3),
+            (":= ", 2),
+            ("fmt.", 4),
+            ("err != nil", 4),
+        ],
+    ),
+    (
+        "java",
+        &[
+            ("public class ", 4),
+            ("System.out.", 4),
+            ("import java.", 4),
+            ("public static void ", 4),
+            ("private ", 1),
+        ],
+    ),
+    (
+        "sh",
+        &[
+            ("echo ", 2),
+            ("fi\n", 3),
+            ("; then", 3),
+            ("done\n", 2),
+            ("esac", 4),
+            ("$(", 2),
+            ("export ", 2),
+        ],
+    ),
+    (
+        "sql",
+        &[
+            ("SELECT ", 3),
+            (" FROM ", 2),
+            (" WHERE ", 2),
+            ("INSERT INTO ", 4),
+            ("CREATE TABLE ", 4),
+        ],
+    ),
+    (
+        "html",
+        &[
+            ("<html", 4),
+            ("<head", 4),
+            ("<body", 4),
+            ("<div", 2),
+            ("</", 1),
+        ],
+    ),
+    (
+        "md",
+        &[
+            ("\n# ", 2),
+            ("\n## ", 3),
+            ("```", 3),
+            ("](", 2),
+            ("\n- ", 1),
+        ],
+    ),
+    ("diff", &[("\n--- ", 2), ("\n+++ ", 3), ("\n@@ ", 4)]),
+    ("yaml", &[("\n---\n", 3), ("\n- ", 1), (": |\n", 4)]),
+];
+
+lazy_static! {
+    static ref VIM: Regex =
+        Regex::new(r"\b(?:vi|vim|ex):.*?\b(?:ft|filetype|syn|syntax)=([\w+#-]+)").unwrap();
+    static ref EMACS: Regex =
+        Regex::new(r"-\*-\s*(?:.*?\bmode:\s*([\w+#-]+).*?|([\w+#-]+)\s*)-\*-").unwrap();
+}
+
+/// The name of the syntax of a paste, going by the name of the uploaded file, a shebang, a
+/// modeline, the first line rules of the syntaxes and finally the contents. `None` if nothing
+/// better than plain text was found.
+pub fn detect(filename: Option<&str>, data: &[u8]) -> Option<String> {
+    let syntax = filename.and_then(by_filename).or_else(|| {
+        let text = text(data)?;
+        let first_line = text.lines().next().unwrap_or_default();
+        by_shebang(first_line)
+            .or_else(|| by_modeline(text))
+            .or_else(|| SYNTAXSET.find_syntax_by_first_line(first_line))
+            .or_else(|| by_content(text))
+    })?;
+    (syntax.name != SYNTAXSET.find_syntax_plain_text().name).then(|| syntax.name.clone())
+}
+
+/// The syntax a paste is highlighted with: the one of the extension in the URL, or else the one
+/// detected when it was created
+pub fn syntax_for(ext: Option<&str>, language: Option<&str>) -> Option<&'static SyntaxReference> {
+    match ext {
+        Some(ext) => SYNTAXSET.find_syntax_by_token(ext),
+        None => language.and_then(|language| SYNTAXSET.find_syntax_by_name(language)),
+    }
+}
+
+/// The sample of `data` to look at, if it is text. `data` can be cut in the middle of a character.
+fn text(data: &[u8]) -> Option<&str> {
+    let data = &data[..data.len().min(SAMPLE)];
+    match std::str::from_utf8(data) {
+        Ok(text) => Some(text),
+        Err(error) if error.error_len().is_none() => {
+            std::str::from_utf8(&data[..error.valid_up_to()]).ok()
+        }
+        Err(_) => None,
+    }
+}
+
+fn by_token(token: &str) -> Option<&'static SyntaxReference> {
+    let token = token.to_ascii_lowercase();
+    let token = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == token)
+        .map_or(token.as_str(), |(_, ext)| *ext);
+    SYNTAXSET.find_syntax_by_token(token)
+}
+
+/// Matches whole file names like `Makefile` before extensions
+fn by_filename(filename: &str) -> Option<&'static SyntaxReference> {
+    SYNTAXSET.find_syntax_by_extension(filename).or_else(|| {
+        let (_, ext) = filename.rsplit_once('.')?;
+        SYNTAXSET
+            .find_syntax_by_extension(ext)
+            .or_else(|| SYNTAXSET.find_syntax_by_extension(&ext.to_ascii_lowercase()))
+    })
+}
+
+/// `#!/bin/bash`, `#!/usr/bin/env -S python3 -u` and the like, ignoring versions
+fn by_shebang(first_line: &str) -> Option<&'static SyntaxReference> {
+    let mut words = first_line.strip_prefix("#!")?.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|word| !word.starts_with('-'))?;
+    }
+    by_token(interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.'))
+}
+
+/// Vim's `vim: set ft=rust:` and Emacs' `-*- mode: python -*-`
+fn by_modeline(text: &str) -> Option<&'static SyntaxReference> {
+    let lines: Vec<&str> = text.lines().collect();
+    let tail = lines
+        .len()
+        .saturating_sub(MODELINE_LINES)
+        .max(MODELINE_LINES);
+    let candidates = lines
+        .iter()
+        .take(MODELINE_LINES)
+        .chain(lines.iter().skip(tail));
+    candidates.find_map(|line| {
+        if let Some(captures) = VIM.captures(line) {
+            return by_token(&captures[1]);
+        }
+        let captures = EMACS.captures(line)?;
+        by_token(captures.get(1).or_else(|| captures.get(2))?.as_str())
+    })
+}
+
+/// Scores the sample against `RULES`, picking a language if it scores well and clearly better
+/// than the runner-up. JSON is recognised by parsing it.
+fn by_content(text: &str) -> Option<&'static SyntaxReference> {
+    let trimmed = text.trim_start();
+    if (trimmed.starts_with('{') || trimmed.starts_with('['))
+        && serde_json::from_str::<serde::de::IgnoredAny>(text).is_ok()
+    {
+        return SYNTAXSET.find_syntax_by_token("json");
+    }
+    // So snippets starting with a newline match on the first line too
+    let text = format!("\n{text}");
+    let mut scores: Vec<(&str, u32)> = RULES
+        .iter()
+        .map(|(ext, snippets)| {
+            let score = snippets
+                .iter()
+                .map(|(snippet, weight)| weight * text.matches(snippet).take(3).count() as u32)
+                .sum();
+            (*ext, score)
+        })
+        .collect();
+    scores.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+    let (ext, best) = scores[0];
+    let runner_up = scores[1].1;
+    if best < MIN_SCORE || best * 2 < runner_up * 3 {
+        return None;
+    }
+    SYNTAXSET.find_syntax_by_token(ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(filename: Option<&str>, data: &str) -> Option<String> {
+        detect(filename, data.as_bytes())
+    }
+
+    #[test]
+    fn detects_languages() {
+        assert_eq!(name(Some("main.rs"), "").as_deref(), Some("Rust"));
+        assert_eq!(name(Some("Makefile"), "").as_deref(), Some("Makefile"));
+        let script = "#!/usr/bin/env python3\nprint('hi')\n";
+        assert_eq!(name(None, script).as_deref(), Some("Python"));
+        let script = "#!/bin/zsh\necho hi\n";
+        assert_eq!(
+            name(None, script).as_deref(),
+            Some("Bourne Again Shell (bash)")
+        );
+        let modeline = "x = 1\n# vim: set ft=ruby:\n";
+        assert_eq!(name(None, modeline).as_deref(), Some("Ruby"));
+        let modeline = "// -*- mode: c++; indent-tabs-mode: nil -*-\nint x;\n";
+        assert_eq!(name(None, modeline).as_deref(), Some("C++"));
+        let xml = "<?xml version=\"1.0\"?>\n<a/>\n";
+        assert_eq!(name(None, xml).as_deref(), Some("XML"));
+        let rust = "use std::io;\n\nfn main() {\n    let mut x = 1;\n    println!(\"{x}\");\n}\n";
+        assert_eq!(name(None, rust).as_deref(), Some("Rust"));
+        assert_eq!(name(None, "{\"a\": [1, 2]}").as_deref(), Some("JSON"));
+        assert_eq!(name(None, "just some words, nothing more"), None);
+        assert_eq!(detect(None, &[0xff, 0xfe, 0x00]), None);
+    }
+
+    #[test]
+    fn extensions_win() {
+        let python = Some("Python");
+        assert_eq!(syntax_for(Some("rs"), python).unwrap().name, "Rust");
+        assert_eq!(syntax_for(None, python).unwrap().name, "Python");
+        assert!(syntax_for(Some("nope"), python).is_none());
+        assert!(syntax_for(None, None).is_none());
+    }
+}
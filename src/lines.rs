@@ -0,0 +1,170 @@
+//! Ranges of lines, which `?lines=10-20` cuts pastes down to and `#L10-L20` highlights in pages
+
+use std::fmt;
+
+use axum::http::StatusCode;
+
+/// Lines numbered from 1, both ends included. Without an end the range runs to the last line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: Option<usize>,
+}
+
+impl LineRange {
+    /// Parses `10`, `10-20` and `10-`, also with the `L` of line anchors like `L10-L20`
+    pub fn parse(lines: &str) -> Option<LineRange> {
+        let number = |n: &str| {
+            let n = n.trim().trim_start_matches('L').parse::<usize>().ok()?;
+            (n > 0).then_some(n)
+        };
+        let range = match lines.split_once('-') {
+            None => {
+                let line = number(lines)?;
+                LineRange {
+                    start: line,
+                    end: Some(line),
+                }
+            }
+            Some((start, "")) => LineRange {
+                start: number(start)?,
+                end: None,
+            },
+            Some((start, end)) => LineRange {
+                start: number(start)?,
+                end: Some(number(end)?),
+            },
+        };
+        range
+            .end
+            .map_or(true, |end| end >= range.start)
+            .then_some(range)
+    }
+
+    /// The range asked for in a query, which has to be valid if it is there at all
+    pub fn from_query(lines: Option<&str>) -> Result<Option<LineRange>, StatusCode> {
+        lines
+            .map(|lines| LineRange::parse(lines).ok_or(StatusCode::BAD_REQUEST))
+            .transpose()
+    }
+
+    pub fn contains(&self, line: usize) -> bool {
+        line >= self.start && self.end.map_or(true, |end| line <= end)
+    }
+
+    /// The lines of `data` in the range, with their line endings. `None` if `data` ends before the
+    /// range starts.
+    pub fn slice<'a>(&self, data: &'a [u8]) -> Option<&'a [u8]> {
+        let mut start = None;
+        let mut offset = 0;
+        for (index, line) in data.split_inclusive(|&b| b == b'\n').enumerate() {
+            if index + 1 == self.start {
+                start = Some(offset);
+            }
+            offset += line.len();
+            if Some(index + 1) == self.end {
+                break;
+            }
+        }
+        Some(&data[start?..offset])
+    }
+}
+
+/// Cuts a range of lines out of data that arrives piece by piece, like the chunks of a large
+/// paste, so nothing after the range has to be read
+pub struct LineSlicer {
+    range: LineRange,
+    /// The number of the line the next piece starts in
+    line: usize,
+    lines: Vec<u8>,
+}
+
+impl LineSlicer {
+    pub fn new(range: LineRange) -> LineSlicer {
+        LineSlicer {
+            range,
+            line: 1,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Adds the next piece of data, returning whether any more of it is needed
+    pub fn push(&mut self, data: &[u8]) -> bool {
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if self.range.contains(self.line) {
+                self.lines.extend_from_slice(line);
+            }
+            if line.ends_with(b"\n") {
+                if Some(self.line) == self.range.end {
+                    return false;
+                }
+                self.line += 1;
+            }
+        }
+        true
+    }
+
+    /// The lines in the range, `None` like `LineRange::slice` if the data ended before it started
+    pub fn finish(self) -> Option<Vec<u8>> {
+        (!self.lines.is_empty()).then_some(self.lines)
+    }
+}
+
+impl fmt::Display for LineRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.end {
+            Some(end) => write!(f, "{}-{end}", self.start),
+            None => write!(f, "{}-", self.start),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        let range = |start, end| Some(LineRange { start, end });
+        assert_eq!(LineRange::parse("10"), range(10, Some(10)));
+        assert_eq!(LineRange::parse("10-20"), range(10, Some(20)));
+        assert_eq!(LineRange::parse("L10-L20"), range(10, Some(20)));
+        assert_eq!(LineRange::parse("10-"), range(10, None));
+        assert_eq!(LineRange::parse("20-10"), None);
+        assert_eq!(LineRange::parse("0-3"), None);
+        assert_eq!(LineRange::parse("a-b"), None);
+        assert_eq!(LineRange::parse("10-").unwrap().to_string(), "10-");
+        assert_eq!(LineRange::parse("L3").unwrap().to_string(), "3-3");
+    }
+
+    #[test]
+    fn slices_lines() {
+        let data = b"one\ntwo\nthree\nfour";
+        let slice = |lines| LineRange::parse(lines).unwrap().slice(data);
+        assert_eq!(slice("2-3"), Some(&b"two\nthree\n"[..]));
+        assert_eq!(slice("3-"), Some(&b"three\nfour"[..]));
+        assert_eq!(slice("4-9"), Some(&b"four"[..]));
+        assert_eq!(slice("1"), Some(&b"one\n"[..]));
+        assert_eq!(slice("5-"), None);
+    }
+
+    #[test]
+    fn slices_pieces_like_whole_data() {
+        let data = b"one\ntwo\nthree\nfour";
+        for lines in ["2-3", "3-", "4-9", "1", "5-"] {
+            let range = LineRange::parse(lines).unwrap();
+            let mut slicer = LineSlicer::new(range);
+            // Pieces are cut in the middle of lines and right after line endings
+            for piece in data.chunks(4) {
+                if !slicer.push(piece) {
+                    break;
+                }
+            }
+            assert_eq!(slicer.finish().as_deref(), range.slice(data), "{lines}");
+        }
+        let mut slicer = LineSlicer::new(LineRange::parse("1-2").unwrap());
+        assert!(slicer.push(b"one\nt"));
+        assert!(!slicer.push(b"wo\nthree"));
+        assert_eq!(slicer.finish().as_deref(), Some(&b"one\ntwo\n"[..]));
+    }
+}
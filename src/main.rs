@@ -2,78 +2,109 @@
 #![allow(dead_code)]
 use axum::response::Redirect;
 use axum::{
-    extract::Path as UrlPath,
-    http::StatusCode,
-    routing::{delete, get, post},
+    extract::{DefaultBodyLimit, Path as UrlPath},
+    http::{HeaderName, HeaderValue, StatusCode},
+    middleware,
+    routing::{delete, get, post, put},
     Router,
 };
-// use clap::Parser;
-use rocksdb::{self, DB};
+use clap::Parser;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use stretto::AsyncCache;
 use tokio::signal;
 use tokio::signal::unix::SignalKind;
+use tower::ServiceBuilder;
+use tower_http::set_header::SetResponseHeaderLayer;
 use url::Url;
 
+mod api;
 mod bot;
+mod chunks;
 mod cli;
+mod conditional;
+mod config;
+mod content_type;
+mod crypto;
 mod handlers_paste;
+mod handlers_revisions;
 mod handlers_shorten;
 mod id;
+mod language;
+mod lines;
+mod password;
+mod ratelimit;
 mod state;
+mod storage;
 mod syntax;
 mod util;
-// use cli::Cli;
+use cli::Cli;
+use config::Config;
 use handlers_paste::*;
+use handlers_revisions::*;
 use handlers_shorten::*;
+use ratelimit::{rate_limit, RateLimiter};
 use state::*;
+use syntax::Themes;
 use util::*;
 
-// TODO: move this to a configuration file and add argument overrides
-const PASTE_ID_LENGTH: usize = 3;
-const URL_ID_LENGTH: usize = 3;
-static IP: &str = "https://oxlink.dev";
-static SOCKETADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 3000);
-static PATH: &str = "db";
-static FILES_DIR: &str = "files";
-static URL_CF: &str = "URL";
-static PASTE_CF: &str = "PASTE";
-static MAX_PASTE_BYTES: usize = 1024 * 128;
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // let config = Cli::parse();
-    let db_cache = rocksdb::Cache::new_lru_cache(128)?;
-    let db = {
-        let mut opts = rocksdb::Options::default();
-        opts.set_compression_type(rocksdb::DBCompressionType::Lz4);
-        opts.create_missing_column_families(true);
-        opts.set_row_cache(&db_cache);
-        opts.create_if_missing(true);
-        // opts.set_merge_operator_associative("increment", incr_merge);
-        opts.set_max_background_jobs(4);
-        Arc::new(DB::open_cf_descriptors(
-            &opts,
-            PATH,
-            util::make_descriptors(rocksdb::Options::default(), vec![URL_CF, PASTE_CF]),
-        )?)
+    let mut cli = Cli::parse();
+    if let Some(command) = cli.command.take() {
+        return command.run();
+    }
+    let level = match cli.debug {
+        0 => tracing::Level::INFO,
+        1 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
     };
+    let config = Config::from_cli(cli)?;
+    let db_cache = rocksdb::Cache::new_lru_cache(128)?;
+    let db = Arc::new(open_db(&config, &db_cache)?);
     let cache = AsyncCache::new(1000, 1024 * 1024 * 50, tokio::spawn)
         .expect("Failed to initialize AsyncCache");
 
     // Configure tracing if desired
     // construct a subscriber that prints formatted traces to stdout
-    let subscriber = tracing_subscriber::FmtSubscriber::new();
+    let subscriber = tracing_subscriber::FmtSubscriber::builder()
+        .with_max_level(level)
+        .finish();
     // use that subscriber to process traces emitted after this point
     tracing::subscriber::set_global_default(subscriber).unwrap();
-    let image = create_image((SIZE.0 as u32, SIZE.1 as u32), 5);
+    let templates = Templates::load(&config)?;
+    let themes = Themes::load(&config)?;
+    syntax::load_syntaxes(&config)?;
+    let rate_limiter = RateLimiter::new(&config);
+    let addr = config.listen;
+    let files_dir = config.files_dir.clone();
     let state = CurState {
-        image: Box::new(image),
+        cards: Arc::new(Mutex::new(HashMap::new())),
         db,
         db_cache,
         cache,
+        config: Arc::new(config),
+        templates: Arc::new(templates),
+        themes: Arc::new(themes),
+        write_lock: Arc::new(Mutex::new(())),
+        rate_limiter: Arc::new(rate_limiter),
     };
+    for cf_name in [&state.config.paste_cf, &state.config.url_cf] {
+        state.migrate(cf_name)?;
+    }
+    if state.config.reap_interval != 0 {
+        tokio::spawn(reap_expired(state.clone()));
+    }
+    tokio::spawn(prune_rate_limits(state.rate_limiter.clone()));
+    // Pastes are streamed and limited while they are stored, this only limits multipart forms
+    let paste_limit = ServiceBuilder::new()
+        .layer(SetResponseHeaderLayer::overriding(
+            HeaderName::from_static(MAX_PASTE_BYTES_HEADER),
+            HeaderValue::from(state.config.max_paste_bytes),
+        ))
+        .layer(DefaultBodyLimit::max(state.config.max_paste_bytes));
     let app = Router::new()
         // .route("/list", get(list))
         .route("/", get(web_paste))
@@ -86,23 +117,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route("/a", get(web_analytics))
         .route("/a/", get(web_analytics))
         .route("/:paste", get(get_paste))
+        .route("/:paste", post(unlock_paste))
         .route("/i/:paste", get(paste_image))
-        // .route("/p/:paste", post(create_paste))
+        .route("/r/:paste", get(raw_paste))
+        .route("/v/:paste", get(list_revisions))
+        .route("/d/:paste", get(diff_paste))
+        .route("/theme/:theme", get(theme_css))
+        .route("/languages", get(languages))
+        .route("/:paste", put(create_paste).layer(paste_limit.clone()))
         .route("/:paste", delete(delete_paste))
-        .nest_service("/files/", util::serve())
-        .route("/", post(new_paste))
+        .nest_service("/files/", util::serve(&files_dir))
+        .route("/", post(new_paste).layer(paste_limit))
         .route("/help/", get(util::help))
         .route("/help", get(util::help))
         .route("/s/:url", get(get_url))
-        // .route("/:url", post(create_url))
+        .route("/s/:url", put(create_url))
         .route("/s/:url", delete(delete_url))
         .route("/s/", post(shorten_url))
         .route("/s", post(shorten_url))
         .route("/s/", get(web_short))
         .route("/s", get(web_short))
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit))
+        .layer(middleware::from_fn(api::json_errors))
         .with_state(state);
 
-    let addr = SocketAddr::from(SOCKETADDR);
     println!("Listening on {}", addr);
     // axum_server::bind_rustls(addr, config)
     axum::Server::bind(&addr)
@@ -115,6 +153,39 @@ pub async fn status() -> &'static str {
     "Ok!"
 }
 
+/// Periodically removes expired pastes and short links from the database
+async fn reap_expired(state: CurState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.reap_interval));
+    loop {
+        interval.tick().await;
+        let state = state.clone();
+        let reaped = tokio::task::spawn_blocking(move || {
+            [&state.config.paste_cf, &state.config.url_cf]
+                .map(|cf_name| (cf_name.clone(), state.reap_expired(cf_name)))
+        })
+        .await;
+        let Ok(reaped) = reaped else { continue };
+        for (cf_name, result) in reaped {
+            match result {
+                Ok(0) => (),
+                Ok(removed) => tracing::info!("Removed {removed} expired entries from {cf_name}"),
+                Err(error) => {
+                    tracing::warn!("Failed to remove expired entries from {cf_name}: {error:?}")
+                }
+            }
+        }
+    }
+}
+
+/// Periodically forgets rate limit buckets of clients that have been quiet for long enough
+async fn prune_rate_limits(rate_limiter: Arc<RateLimiter>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        rate_limiter.prune();
+    }
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
@@ -0,0 +1,142 @@
+//! Pastes protected by a password the server checks, stored as an Argon2 hash. Clients send it in
+//! an `Authorization: Basic` header, browsers without one get a form that posts it instead.
+
+use crate::ratelimit::{too_many_requests, Budget};
+use crate::state::{CurState, Entry};
+use crate::StatusCode;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::response::{Html, IntoResponse, Response};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::net::IpAddr;
+
+/// The header a password is sent in when creating a paste
+pub static PASSWORD_HEADER: &str = "x-password";
+
+/// Hashes a password into a PHC string, which holds its salt and parameters
+pub fn hash(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+pub fn verify(hash: &str, password: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false};
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok()
+}
+
+/// `hash` on a blocking thread, as Argon2 takes tens of milliseconds and ~19 MiB of memory
+pub async fn hash_blocking(password: &str) -> Option<String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || hash(&password).ok())
+        .await
+        .ok()
+        .flatten()
+}
+
+/// The password a request sent for a protected paste, along with the client that sent it
+#[derive(Clone, Copy)]
+pub struct Credentials<'a> {
+    pub ip: IpAddr,
+    pub password: Option<&'a str>,
+}
+
+/// Whether the credentials unlock `entry`, which anything does if it isn't protected.
+///
+/// The password is verified on a blocking thread, like `hash_blocking` hashes it. Every wrong
+/// password is charged to the client's unlock budget, and once that is used up the 429 to send
+/// is returned without verifying anything, so passwords can only be guessed a few times a minute.
+pub async fn unlock(
+    state: &CurState,
+    entry: &Entry,
+    credentials: Credentials<'_>,
+) -> Result<bool, Response> {
+    let Some(hash) = &entry.password_hash else {
+        return Ok(true)};
+    let Some(password) = credentials.password else {
+        return Ok(false)};
+    let limiter = &state.rate_limiter;
+    limiter
+        .check(credentials.ip, Budget::Unlock)
+        .map_err(too_many_requests)?;
+    let (hash, password) = (hash.clone(), password.to_string());
+    let unlocked = tokio::task::spawn_blocking(move || verify(&hash, &password))
+        .await
+        .unwrap_or(false);
+    if unlocked {
+        limiter.refund(credentials.ip, Budget::Unlock);
+    }
+    Ok(unlocked)
+}
+
+/// The password in an `Authorization: Basic` header, whatever the user name
+pub fn basic_auth(headers: &HeaderMap) -> Option<String> {
+    let credentials = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let credentials = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+    let (_, password) = credentials.split_once(':')?;
+    Some(password.to_string())
+}
+
+/// The response to a request for a protected paste without the right password. Browsers get a
+/// form, as the prompt for a Basic auth challenge can't be styled or explained.
+pub fn password_required(html: bool, wrong: bool) -> (StatusCode, Response) {
+    if !html {
+        let mut response = "This paste is protected by a password".into_response();
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"OxiiLink paste\", charset=\"UTF-8\""),
+        );
+        return (StatusCode::UNAUTHORIZED, response);
+    }
+    let hint = if wrong { "<p>Wrong password.</p>" } else { "" };
+    let page = Html(format!(
+        "<!DOCTYPE html>
+<html><head>
+<meta charset='utf-8'>
+<title>OxiiLink - Protected paste</title>
+<link rel='stylesheet' href='/files/style.css'>
+</head><body>
+<p>This paste is protected by a password.</p>
+{hint}<form method='post'>
+<input class='input' type='password' name='password' placeholder='Password' autofocus>
+<button class='input' type='submit'>Unlock</button>
+</form>
+</body></html>"
+    ));
+    (StatusCode::UNAUTHORIZED, page.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_passwords() {
+        let hash = hash("hunter2").unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify(&hash, "hunter2"));
+        assert!(!verify(&hash, "hunter3"));
+        assert!(!verify("not a hash", "hunter2"));
+    }
+
+    #[test]
+    fn reads_basic_auth() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(basic_auth(&headers), None);
+        // "anyone:pass:word"
+        let value = HeaderValue::from_static("Basic YW55b25lOnBhc3M6d29yZA==");
+        headers.insert(header::AUTHORIZATION, value);
+        assert_eq!(basic_auth(&headers).as_deref(), Some("pass:word"));
+    }
+}
@@ -0,0 +1,231 @@
+use crate::config::Config;
+use crate::state::CurState;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, HeaderMap, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The kinds of requests that are limited separately from each other
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Budget {
+    Create,
+    Delete,
+    Read,
+    Image,
+    /// Wrong passwords for protected pastes, charged by `password::unlock` rather than per request
+    Unlock,
+}
+
+impl Budget {
+    /// The budget a request is charged to, `None` if it isn't limited at all
+    pub fn of(method: &Method, path: &str) -> Option<Budget> {
+        match *method {
+            // Posting to a paste sends the password form, which reads the paste like a GET with
+            // the password would. Wrong passwords are charged to `Unlock` on top.
+            Method::POST if !matches!(path, "/" | "/s" | "/s/") => Some(Budget::Read),
+            Method::POST | Method::PUT => Some(Budget::Create),
+            Method::DELETE => Some(Budget::Delete),
+            _ if path.starts_with("/files/") || path == "/status" => None,
+            _ if path.starts_with("/i/") => Some(Budget::Image),
+            _ => Some(Budget::Read),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter keyed by client IP.
+///
+/// Every budget allows a burst of its per minute limit and refills continuously at that rate.
+pub struct RateLimiter {
+    create: u32,
+    delete: u32,
+    read: u32,
+    image: u32,
+    unlock: u32,
+    trust_forwarded_for: bool,
+    buckets: Mutex<HashMap<(IpAddr, Budget), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        RateLimiter {
+            create: config.rate_limit_create,
+            delete: config.rate_limit_delete,
+            read: config.rate_limit_read,
+            image: config.rate_limit_image,
+            unlock: config.rate_limit_unlock,
+            trust_forwarded_for: config.trust_forwarded_for,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Requests allowed per minute for a budget, 0 for no limit
+    fn per_minute(&self, budget: Budget) -> u32 {
+        match budget {
+            Budget::Create => self.create,
+            Budget::Delete => self.delete,
+            Budget::Read => self.read,
+            Budget::Image => self.image,
+            Budget::Unlock => self.unlock,
+        }
+    }
+    /// Takes a token from the client's bucket, or returns how long until one is available
+    pub fn check(&self, ip: IpAddr, budget: Budget) -> Result<(), Duration> {
+        let limit = self.per_minute(budget);
+        if limit == 0 {
+            return Ok(());
+        }
+        let capacity = limit as f64;
+        let rate = capacity / 60.0;
+        let now = Instant::now();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let bucket = buckets.entry((ip, budget)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+    /// Gives back a token taken by `check`, for attempts that turned out not to count
+    pub fn refund(&self, ip: IpAddr, budget: Budget) {
+        let capacity = self.per_minute(budget) as f64;
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(bucket) = buckets.get_mut(&(ip, budget)) {
+            bucket.tokens = (bucket.tokens + 1.0).min(capacity);
+        }
+    }
+    /// Forgets the buckets that have refilled completely, as they are the same as new ones
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        buckets.retain(|(_, budget), bucket| {
+            let capacity = self.per_minute(*budget) as f64;
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * capacity / 60.0 < capacity
+        });
+    }
+    /// The IP the request came from. Behind a trusted proxy, that is the last address the proxy
+    /// appended to `X-Forwarded-For`, the ones before it are up to the client.
+    fn client_ip(&self, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+        if self.trust_forwarded_for {
+            let forwarded = headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .last()
+                .and_then(|ip| ip.trim().parse().ok());
+            if let Some(ip) = forwarded {
+                return ip;
+            }
+        }
+        peer.ip()
+    }
+}
+
+/// The IP a request came from as the rate limiter saw it, added to every request by `rate_limit`
+/// for handlers charging budgets themselves
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+/// Middleware answering with 429 once a client has used up the budget for a request
+pub async fn rate_limit<B>(
+    State(state): State<CurState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let limiter = &state.rate_limiter;
+    let ip = limiter.client_ip(request.headers(), peer);
+    request.extensions_mut().insert(ClientIp(ip));
+    let Some(budget) = Budget::of(request.method(), request.uri().path()) else {
+        return next.run(request).await};
+    match limiter.check(ip, budget) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => too_many_requests(wait),
+    }
+}
+
+/// 429, telling the client how long to wait
+pub fn too_many_requests(wait: Duration) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(
+            header::RETRY_AFTER,
+            (wait.as_secs_f64().ceil() as u64).max(1).to_string(),
+        )],
+        "Too many requests, slow down.",
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_run_dry() {
+        let limiter = RateLimiter::new(&Config {
+            rate_limit_create: 2,
+            ..Config::default()
+        });
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        assert!(limiter.check(ip, Budget::Create).is_ok());
+        assert!(limiter.check(ip, Budget::Create).is_ok());
+        let wait = limiter.check(ip, Budget::Create).unwrap_err();
+        assert!(wait > Duration::from_secs(25) && wait <= Duration::from_secs(30));
+        // Other budgets and clients are unaffected
+        assert!(limiter.check(ip, Budget::Read).is_ok());
+        assert!(limiter
+            .check(IpAddr::from([10, 0, 0, 1]), Budget::Create)
+            .is_ok());
+    }
+
+    #[test]
+    fn charges_by_route() {
+        assert_eq!(Budget::of(&Method::POST, "/"), Some(Budget::Create));
+        assert_eq!(Budget::of(&Method::POST, "/s/"), Some(Budget::Create));
+        assert_eq!(Budget::of(&Method::PUT, "/abc"), Some(Budget::Create));
+        assert_eq!(Budget::of(&Method::POST, "/abc"), Some(Budget::Read));
+        assert_eq!(Budget::of(&Method::DELETE, "/abc"), Some(Budget::Delete));
+        assert_eq!(Budget::of(&Method::GET, "/i/abc"), Some(Budget::Image));
+        assert_eq!(Budget::of(&Method::GET, "/status"), None);
+    }
+
+    #[test]
+    fn refunds_tokens() {
+        let limiter = RateLimiter::new(&Config {
+            rate_limit_unlock: 1,
+            ..Config::default()
+        });
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        // A right password gives its token back, a wrong one doesn't
+        assert!(limiter.check(ip, Budget::Unlock).is_ok());
+        limiter.refund(ip, Budget::Unlock);
+        assert!(limiter.check(ip, Budget::Unlock).is_ok());
+        assert!(limiter.check(ip, Budget::Unlock).is_err());
+    }
+}
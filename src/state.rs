@@ -1,15 +1,37 @@
-use crate::Arc;
+use crate::chunks::{self, Chunks};
+use crate::config::Config;
+use crate::content_type;
+use crate::id::{self, Id};
+use crate::lines::{LineRange, LineSlicer};
+use crate::ratelimit::RateLimiter;
+use crate::storage::{self, FORMAT_VERSION};
+use crate::syntax::{Themes, BUILTIN_THEME};
+use crate::util::{create_image, make_descriptors, Templates, SIZE};
+use crate::{Arc, StatusCode};
 use chrono::{self, Utc};
 use image::{ImageBuffer, Rgba};
 use rkyv::{Archive, Deserialize, Serialize};
+use rocksdb::properties::ESTIMATE_NUM_KEYS;
+use rocksdb::{MergeOperands, DB};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
 use stretto::AsyncCache;
 
 #[derive(Clone)]
 pub struct CurState {
-    pub db: Arc<crate::rocksdb::DB>,
+    pub db: Arc<DB>,
     pub db_cache: rocksdb::Cache,
     pub cache: AsyncCache<String, Vec<u8>>,
-    pub image: Box<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    /// The blank embed card of each theme, by slug, drawn the first time it is needed
+    pub cards: Arc<Mutex<HashMap<String, Arc<ImageBuffer<Rgba<u8>, Vec<u8>>>>>>,
+    pub config: Arc<Config>,
+    pub templates: Arc<Templates>,
+    pub themes: Arc<Themes>,
+    /// Held while checking for an entry and then writing or deleting it, so concurrent requests
+    /// can't race each other, e.g. two readers both getting a burn-after-reading paste. Reference
+    /// counts of paste bodies only change with it held too.
+    pub write_lock: Arc<Mutex<()>>,
+    pub rate_limiter: Arc<RateLimiter>,
 }
 
 #[derive(Debug)]
@@ -17,55 +39,686 @@ pub enum DBFailure {
     Error(rocksdb::Error),
     CfError,
     SerError,
+    /// The stored record failed validation
+    Corrupt,
+    /// The stored record was written by a newer version of the server
+    UnknownVersion(u8),
+    /// The blocking task doing the work panicked or was cancelled
+    Aborted,
 }
 
+impl From<DBFailure> for StatusCode {
+    fn from(_: DBFailure) -> Self {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// The current format of stored records, see `storage` for older formats
 #[derive(Archive, Deserialize, Serialize, Debug)]
+#[archive(check_bytes)]
 pub struct Entry {
-    pub views: u32,
-    pub scrapes: u32,
     pub contents: Vec<u8>,
     pub creationdate: i64,
-    pub obfuscate: bool,
+    /// Whether the contents were encrypted by the client, see `crypto`. Older formats called this
+    /// `obfuscate` and never set it.
+    pub encrypted: bool,
+    /// BLAKE3 hash of the token required to delete this entry, all zeroes if it can't be deleted
+    pub delete_hash: [u8; 32],
+    /// Unix timestamp after which this entry is gone
+    pub expiry: Option<i64>,
+    /// Whether this entry is deleted the first time it is read
+    pub burn_after_reading: bool,
+    /// The name of the file the paste was uploaded from
+    pub filename: Option<String>,
+    /// The MIME type of the contents, without parameters
+    pub content_type: String,
+    /// Where the rest of the contents is stored if they didn't fit in the record
+    pub chunks: Option<Chunks>,
+    /// BLAKE3 hash of the contents if they are stored as a `PasteBody`, shared by identical
+    /// pastes. The record then holds no contents or chunks itself, `CurState::get` loads them.
+    pub hash: Option<[u8; 32]>,
+    /// Argon2 hash of the password required to read this entry, see `password`
+    pub password_hash: Option<String>,
+    /// The name of the syntax detected for the paste when it was created, see `language`
+    pub language: Option<String>,
+    /// How many earlier revisions of the paste are stored in `revision_cf`, numbered from 1
+    pub revisions: u32,
 }
 
 impl Entry {
-    pub fn new<V>(contents: V, views: u32, scrapes: u32, obfuscate: bool) -> Self
+    pub fn new<V>(contents: V, encrypted: bool) -> Self
     where
         Vec<u8>: std::convert::From<V>,
     {
+        let contents: Vec<u8> = contents.into();
         Entry {
-            views,
-            scrapes,
-            contents: contents.into(),
+            content_type: content_type::detect(None, &contents),
+            contents,
             creationdate: Utc::now().timestamp(),
-            obfuscate,
+            encrypted,
+            delete_hash: [0; 32],
+            expiry: None,
+            burn_after_reading: false,
+            filename: None,
+            chunks: None,
+            hash: None,
+            password_hash: None,
+            language: None,
+            revisions: 0,
+        }
+    }
+    pub fn with_burn_after_reading(mut self, burn_after_reading: bool) -> Self {
+        self.burn_after_reading = burn_after_reading;
+        self
+    }
+    /// Uses the type the client declared for the contents, if it is specific enough
+    pub fn with_content_type(mut self, declared: Option<&str>) -> Self {
+        self.content_type = content_type::detect(declared, &self.contents);
+        self
+    }
+    pub fn with_chunks(mut self, chunks: Option<Chunks>) -> Self {
+        self.chunks = chunks;
+        self
+    }
+    pub fn with_hash(mut self, hash: Option<[u8; 32]>) -> Self {
+        self.hash = hash;
+        self
+    }
+    /// The hash of the contents, computed for records from before bodies were stored by hash
+    /// unless they are too large to hash in memory
+    pub fn content_hash(&self) -> Option<[u8; 32]> {
+        match (self.hash, &self.chunks) {
+            (Some(hash), _) => Some(hash),
+            (None, None) => Some(*blake3::hash(&self.contents).as_bytes()),
+            (None, Some(_)) => None,
+        }
+    }
+    /// The record stored for this entry, without the contents if they are stored as a body
+    fn record(&self) -> Result<Vec<u8>, DBFailure> {
+        if self.hash.is_none() {
+            return storage::encode::<256>(self);
+        }
+        storage::encode::<256>(&Entry {
+            contents: Vec::new(),
+            chunks: None,
+            filename: self.filename.clone(),
+            content_type: self.content_type.clone(),
+            password_hash: self.password_hash.clone(),
+            language: self.language.clone(),
+            ..*self
+        })
+    }
+    /// The size of the whole paste, `contents` only being the start of large ones
+    pub fn size(&self) -> u64 {
+        self.chunks
+            .as_ref()
+            .map_or(self.contents.len() as u64, |chunks| chunks.size)
+    }
+    pub fn with_filename(mut self, filename: Option<String>) -> Self {
+        self.filename = filename;
+        self
+    }
+    pub fn with_language(mut self, language: Option<String>) -> Self {
+        self.language = language;
+        self
+    }
+    /// The extension of the uploaded file, used to pick a syntax when none is given in the URL
+    pub fn extension(&self) -> Option<&str> {
+        let filename = self.filename.as_deref()?;
+        std::path::Path::new(filename)
+            .extension()
+            .and_then(|ext| ext.to_str())
+    }
+    pub fn with_expiry(mut self, expiry: Option<i64>) -> Self {
+        self.expiry = expiry;
+        self
+    }
+    pub fn is_expired(&self) -> bool {
+        self.expiry
+            .map_or(false, |expiry| expiry <= Utc::now().timestamp())
+    }
+    pub fn with_delete_token(mut self, token: &str) -> Self {
+        self.delete_hash = *blake3::hash(token.as_bytes()).as_bytes();
+        self
+    }
+    pub fn with_password_hash(mut self, password_hash: Option<String>) -> Self {
+        self.password_hash = password_hash;
+        self
+    }
+    /// Whether someone holds a token for this entry, allowing them to change it
+    pub fn has_owner(&self) -> bool {
+        self.delete_hash != [0; 32]
+    }
+    pub fn check_delete_token(&self, token: &str) -> bool {
+        // blake3::Hash compares in constant time
+        self.has_owner() && blake3::Hash::from(self.delete_hash) == blake3::hash(token.as_bytes())
+    }
+}
+
+/// The body of a paste, stored once under the BLAKE3 hash of its contents
+#[derive(Archive, Deserialize, Serialize, Debug)]
+#[archive(check_bytes)]
+pub struct PasteBody {
+    /// The contents, or their first chunk if they are stored in `chunks`
+    pub contents: Vec<u8>,
+    pub chunks: Option<Chunks>,
+}
+
+impl PasteBody {
+    /// The size of the whole paste, like `Entry::size`
+    pub fn size(&self) -> u64 {
+        self.chunks
+            .as_ref()
+            .map_or(self.contents.len() as u64, |chunks| chunks.size)
+    }
+}
+
+/// The key the number of pastes referring to a body is stored under, never as long as a hash
+fn refs_key(hash: &[u8; 32]) -> Vec<u8> {
+    [b"refs/".as_slice(), hash].concat()
+}
+
+/// The key an earlier revision of `key` in `cf_name` is stored under, namespaced like its
+/// counters as IDs are only unique within a column family. IDs never contain '@'.
+fn revision_key(cf_name: &str, key: &[u8], revision: u32) -> Vec<u8> {
+    let mut key = counter_key(cf_name, key);
+    key.extend_from_slice(format!("@{revision}").as_bytes());
+    key
+}
+
+/// The range of keys the earlier revisions of `key` are stored under, from `key@` up to the
+/// character after '@'
+fn revision_range(key: &[u8]) -> std::ops::Range<Vec<u8>> {
+    [key, b"@"].concat()..[key, b"A"].concat()
+}
+
+/// Once this share of the IDs of a length is estimated to be in use, new IDs are generated one
+/// character longer
+const MAX_ID_FILL: f64 = 0.25;
+/// How many random IDs of a length are tried before falling back to a longer one
+const ID_ATTEMPTS: usize = 8;
+
+pub fn open_db(config: &Config, db_cache: &rocksdb::Cache) -> Result<DB, rocksdb::Error> {
+    let mut opts = rocksdb::Options::default();
+    opts.set_compression_type(rocksdb::DBCompressionType::Lz4);
+    opts.create_missing_column_families(true);
+    opts.set_row_cache(db_cache);
+    opts.create_if_missing(true);
+    opts.set_max_background_jobs(4);
+    let mut descriptors = make_descriptors(
+        rocksdb::Options::default(),
+        vec![
+            config.url_cf.as_str(),
+            config.paste_cf.as_str(),
+            config.revision_cf.as_str(),
+        ],
+    );
+    let mut counter_opts = rocksdb::Options::default();
+    counter_opts.set_merge_operator_associative("increment", counter_merge);
+    descriptors.push(rocksdb::ColumnFamilyDescriptor::new(
+        &config.counter_cf,
+        counter_opts,
+    ));
+    // Chunks are large and never change, so they are kept in blob files instead of being
+    // rewritten by every compaction
+    let mut chunk_opts = rocksdb::Options::default();
+    chunk_opts.set_enable_blob_files(true);
+    chunk_opts.set_enable_blob_gc(true);
+    descriptors.push(rocksdb::ColumnFamilyDescriptor::new(
+        &config.chunk_cf,
+        chunk_opts,
+    ));
+    descriptors.push(rocksdb::ColumnFamilyDescriptor::new(
+        &config.body_cf,
+        rocksdb::Options::default(),
+    ));
+    DB::open_cf_descriptors(&opts, &config.db_path, descriptors)
+}
+
+/// View counters of an entry, kept in their own column family so counting a view never has to
+/// rewrite the entry itself
+#[derive(Default, Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct Counters {
+    pub views: u64,
+    pub scrapes: u64,
+    /// Unix timestamp of the last view, 0 if it was never viewed
+    pub last_viewed: i64,
+}
+
+impl Counters {
+    pub fn to_bytes(self) -> [u8; 24] {
+        let mut bytes = [0; 24];
+        bytes[..8].copy_from_slice(&self.views.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.scrapes.to_le_bytes());
+        bytes[16..].copy_from_slice(&self.last_viewed.to_le_bytes());
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let field = |range: std::ops::Range<usize>| {
+            bytes
+                .get(range)
+                .map_or([0; 8], |field| field.try_into().unwrap())
+        };
+        Counters {
+            views: u64::from_le_bytes(field(0..8)),
+            scrapes: u64::from_le_bytes(field(8..16)),
+            last_viewed: i64::from_le_bytes(field(16..24)),
         }
     }
 }
 
+/// RocksDB merge operator adding up `Counters`, so concurrent views are never lost
+pub fn counter_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut total = existing.map(Counters::from_bytes).unwrap_or_default();
+    for operand in operands {
+        let operand = Counters::from_bytes(operand);
+        total.views = total.views.saturating_add(operand.views);
+        total.scrapes = total.scrapes.saturating_add(operand.scrapes);
+        total.last_viewed = total.last_viewed.max(operand.last_viewed);
+    }
+    Some(total.to_bytes().to_vec())
+}
+
+/// The key the counters of `key` in `cf_name` are stored under
+fn counter_key(cf_name: &str, key: &[u8]) -> Vec<u8> {
+    [cf_name.as_bytes(), b"/", key].concat()
+}
+
 impl CurState {
+    /// The blank embed card drawn in a theme, `None` being the built-in one
+    pub fn card(&self, theme: Option<&str>) -> Arc<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let slug = theme.unwrap_or(BUILTIN_THEME);
+        let cards = || {
+            self.cards
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+        };
+        if let Some(card) = cards().get(slug) {
+            return card.clone();
+        }
+        // Drawn without holding the lock, so requests for other cards don't wait for it. Two
+        // requests may both draw a new card, the first one stored is kept.
+        let (_, palette) = self.themes.for_image(theme);
+        let card = Arc::new(create_image((SIZE.0 as u32, SIZE.1 as u32), 5, &palette));
+        cards().entry(slug.to_string()).or_insert(card).clone()
+    }
+    /// Runs `f` on a thread where blocking is fine. Async handlers make all their database calls
+    /// in here, so neither disk reads nor waiting for `write_lock` stall the async workers.
+    pub async fn blocking<T, F>(&self, f: F) -> Result<T, DBFailure>
+    where
+        F: FnOnce(&CurState) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || f(&state))
+            .await
+            .map_err(|_| DBFailure::Aborted)
+    }
+    /// Takes `write_lock`, which nothing panics while holding that would leave the database in a
+    /// bad state, as every change is written in one batch
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.write_lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    /// Counts a view of an entry, or a scrape if it was fetched by a bot
+    pub fn count_view<K>(&self, key: K, cf_name: &str, bot: bool) -> Result<(), DBFailure>
+    where
+        K: AsRef<[u8]>,
+    {
+        let Some(cf) = self.db.cf_handle(&self.config.counter_cf) else {
+            return Err(DBFailure::CfError)};
+        let delta = Counters {
+            views: !bot as u64,
+            scrapes: bot as u64,
+            last_viewed: if bot { 0 } else { Utc::now().timestamp() },
+        };
+        self.db
+            .merge_cf(&cf, counter_key(cf_name, key.as_ref()), delta.to_bytes())
+            .map_err(DBFailure::Error)
+    }
+    pub fn counters<K>(&self, key: K, cf_name: &str) -> Result<Counters, DBFailure>
+    where
+        K: AsRef<[u8]>,
+    {
+        let Some(cf) = self.db.cf_handle(&self.config.counter_cf) else {
+            return Err(DBFailure::CfError)};
+        match self
+            .db
+            .get_pinned_cf(&cf, counter_key(cf_name, key.as_ref()))
+        {
+            Err(error) => Err(DBFailure::Error(error)),
+            Ok(None) => Ok(Counters::default()),
+            Ok(Some(value)) => Ok(Counters::from_bytes(&value)),
+        }
+    }
+    /// Whether an entry is stored under `key`. Expired entries the reaper didn't get to yet don't
+    /// count, they are replaced like absent ones.
     pub fn key_exists<'a, K>(&'a self, key: K, cf_name: &'a str) -> Result<bool, DBFailure>
+    where
+        K: AsRef<[u8]>,
+    {
+        let entry = self.get_record(key, cf_name)?;
+        Ok(entry.map_or(false, |entry| !entry.is_expired()))
+    }
+    /// Stores `entry` under `key` unless the key is already taken, returning whether it was stored.
+    /// An expired entry under `key` is deleted to make room.
+    pub fn insert_if_absent<K>(
+        &self,
+        key: K,
+        entry: &Entry,
+        cf_name: &str,
+    ) -> Result<bool, DBFailure>
     where
         K: AsRef<[u8]>,
     {
         let Some(cf) = self.db.cf_handle(cf_name) else {
             return Err(DBFailure::CfError)};
-        match self.db.get_pinned_cf(&cf, key) {
-            Err(error) => Err(DBFailure::Error(error)),
-            Ok(None) => Ok(false),
-            Ok(Some(_)) => Ok(true),
+        let value = entry.record()?;
+        let key = key.as_ref();
+        let _guard = self.lock();
+        if let Some(current) = self.get_record(key, cf_name)? {
+            if !current.is_expired() {
+                return Ok(false);
+            }
+            // Written before the new entry, which might share the body this releases
+            self.delete_locked(key, cf_name)?;
+        }
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.put_cf(&cf, key, value);
+        self.take_body(&mut batch, entry)?;
+        self.db.write(batch).map_err(DBFailure::Error)?;
+        Ok(true)
+    }
+    /// Stores `entry` under a new random ID at least `min_length` long, returning the ID.
+    ///
+    /// IDs get longer as the column family fills up, so random IDs rarely collide.
+    pub fn insert_new(
+        &self,
+        entry: &Entry,
+        cf_name: &str,
+        min_length: usize,
+    ) -> Result<String, DBFailure> {
+        let Some(cf) = self.db.cf_handle(cf_name) else {
+            return Err(DBFailure::CfError)};
+        let keys = self
+            .db
+            .property_int_value_cf(&cf, ESTIMATE_NUM_KEYS)
+            .map_err(DBFailure::Error)?
+            .unwrap_or(0);
+        let mut length = min_length.max(1);
+        while keys as f64 >= Id::possible_ids(length) as f64 * MAX_ID_FILL {
+            length += 1;
+        }
+        let value = entry.record()?;
+        let _guard = self.lock();
+        loop {
+            for _ in 0..ID_ATTEMPTS {
+                let id = Id::new(length).into_string();
+                if !id::is_reserved(&id)
+                    && self
+                        .db
+                        .get_pinned_cf(&cf, &id)
+                        .map_err(DBFailure::Error)?
+                        .is_none()
+                {
+                    let mut batch = rocksdb::WriteBatch::default();
+                    batch.put_cf(&cf, &id, &value);
+                    self.take_body(&mut batch, entry)?;
+                    self.db.write(batch).map_err(DBFailure::Error)?;
+                    return Ok(id);
+                }
+            }
+            tracing::debug!(
+                "No free ID of length {length} found in {cf_name}, trying a longer one"
+            );
+            length += 1;
         }
     }
+    /// Deletes an entry along with its counters, chunks and earlier revisions
     pub fn delete<'a, K>(&'a self, key: K, cf_name: &'a str) -> Result<(), DBFailure>
     where
         K: AsRef<[u8]>,
     {
-        let Some(cf) = self.db.cf_handle(cf_name) else {
+        let _guard = self.lock();
+        self.delete_locked(key.as_ref(), cf_name)
+    }
+    /// Deletes an entry like `delete` and returns it with its whole contents, `None` if it was
+    /// already gone, so an entry burned after reading is only ever read once
+    pub fn take<'a, K>(&'a self, key: K, cf_name: &'a str) -> Result<Option<Entry>, DBFailure>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        let _guard = self.lock();
+        let Some(mut entry) = self.get(key, cf_name)? else {
+            return Ok(None)};
+        // The chunks are deleted along with the entry, so they have to be read first
+        entry.contents = self.read_contents(&entry)?;
+        entry.chunks = None;
+        self.delete_locked(key, cf_name)?;
+        Ok(Some(entry))
+    }
+    /// `delete` with `write_lock` already held. Everything is deleted in one batch, releasing the
+    /// bodies of the entry and its revisions in it too.
+    fn delete_locked(&self, key: &[u8], cf_name: &str) -> Result<(), DBFailure> {
+        let (Some(cf), Some(revision_cf)) = (self.db.cf_handle(cf_name), self.db.cf_handle(&self.config.revision_cf)) else {
+            return Err(DBFailure::CfError)};
+        let (Some(counter_cf), Some(chunk_cf)) = (self.db.cf_handle(&self.config.counter_cf), self.db.cf_handle(&self.config.chunk_cf)) else {
             return Err(DBFailure::CfError)};
-        match self.db.delete_cf(&cf, key) {
+        let Some(entry) = self.get_record(key, cf_name)? else {
+            return Ok(())};
+        let mut records = Vec::new();
+        for revision in 1..=entry.revisions {
+            let key = revision_key(cf_name, key, revision);
+            records.extend(self.get_record(key, &self.config.revision_cf)?);
+        }
+        records.push(entry);
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut hashes = Vec::new();
+        for record in records {
+            match (record.hash, &record.chunks) {
+                (Some(hash), _) => hashes.push(hash),
+                (None, Some(chunks)) => {
+                    let keys = chunks::chunk_keys(&chunks.key);
+                    batch.delete_range_cf(&chunk_cf, keys.start, keys.end);
+                }
+                (None, None) => (),
+            }
+        }
+        let revisions = revision_range(&counter_key(cf_name, key));
+        batch.delete_range_cf(&revision_cf, revisions.start, revisions.end);
+        batch.delete_cf(&cf, key);
+        batch.delete_cf(&counter_cf, counter_key(cf_name, key));
+        self.release_bodies(&mut batch, &hashes)?;
+        self.db.write(batch).map_err(DBFailure::Error)
+    }
+    /// The number of the revision stored under `key` itself, the first one being 1
+    pub fn latest_revision(&self, key: &str, cf_name: &str) -> Result<u32, DBFailure> {
+        let record = self.get_record(key, cf_name)?;
+        Ok(record.map_or(0, |record| record.revisions) + 1)
+    }
+    /// Gets a revision of an entry, loading its contents like `get`
+    pub fn get_revision(
+        &self,
+        key: &str,
+        revision: u32,
+        cf_name: &str,
+    ) -> Result<Option<Entry>, DBFailure> {
+        let latest = self.latest_revision(key, cf_name)?;
+        if revision == latest {
+            return self.get(key, cf_name);
+        }
+        if revision == 0 || revision > latest {
+            return Ok(None);
+        }
+        let key = revision_key(cf_name, key.as_bytes(), revision);
+        self.get(key, &self.config.revision_cf)
+    }
+    /// Stores `entry` as the latest revision of `key`, keeping the current one as an earlier
+    /// revision. Returns the number of the new revision, `None` if there is nothing under `key`.
+    pub fn put_revision(
+        &self,
+        key: &str,
+        entry: &mut Entry,
+        cf_name: &str,
+    ) -> Result<Option<u32>, DBFailure> {
+        let (Some(cf), Some(revision_cf)) = (self.db.cf_handle(cf_name), self.db.cf_handle(&self.config.revision_cf)) else {
+            return Err(DBFailure::CfError)};
+        let _guard = self.lock();
+        // The record is moved without its body, so it keeps its reference to it
+        let Some(current) = self.get_record(key, cf_name)? else {
+            return Ok(None)};
+        let previous = current.revisions + 1;
+        entry.revisions = previous;
+        let mut batch = rocksdb::WriteBatch::default();
+        let revision_key = revision_key(cf_name, key.as_bytes(), previous);
+        batch.put_cf(&revision_cf, revision_key, current.record()?);
+        batch.put_cf(&cf, key, entry.record()?);
+        self.take_body(&mut batch, entry)?;
+        self.db.write(batch).map_err(DBFailure::Error)?;
+        Ok(Some(previous + 1))
+    }
+    pub fn get_body(&self, hash: &[u8; 32]) -> Result<Option<PasteBody>, DBFailure> {
+        let Some(cf) = self.db.cf_handle(&self.config.body_cf) else {
+            return Err(DBFailure::CfError)};
+        match self.db.get_pinned_cf(&cf, hash) {
             Err(error) => Err(DBFailure::Error(error)),
-            Ok(_) => Ok(()),
+            Ok(None) => Ok(None),
+            Ok(Some(value)) => storage::decode_body(&value).map(Some),
+        }
+    }
+    /// How many pastes refer to a body
+    fn body_refs(&self, hash: &[u8; 32]) -> Result<u64, DBFailure> {
+        let Some(cf) = self.db.cf_handle(&self.config.body_cf) else {
+            return Err(DBFailure::CfError)};
+        let refs = self
+            .db
+            .get_pinned_cf(&cf, refs_key(hash))
+            .map_err(DBFailure::Error)?;
+        Ok(refs.map_or(0, |refs| {
+            u64::from_le_bytes(<[u8; 8]>::try_from(&refs[..]).unwrap_or_default())
+        }))
+    }
+    /// Adds taking a reference to the body of `entry` to `batch`, if it has one. The body is
+    /// stored along with it unless an identical one already is, then the chunks uploaded for
+    /// `entry` are deleted instead. `write_lock` has to be held until the batch is written.
+    fn take_body(&self, batch: &mut rocksdb::WriteBatch, entry: &Entry) -> Result<(), DBFailure> {
+        let Some(hash) = &entry.hash else {
+            return Ok(())};
+        let (Some(cf), Some(chunk_cf)) = (self.db.cf_handle(&self.config.body_cf), self.db.cf_handle(&self.config.chunk_cf)) else {
+            return Err(DBFailure::CfError)};
+        let refs = self.body_refs(hash)?;
+        if refs == 0 {
+            let body = PasteBody {
+                contents: entry.contents.clone(),
+                chunks: entry.chunks.clone(),
+            };
+            batch.put_cf(&cf, hash, storage::encode_body(&body)?);
+        } else if let Some(chunks) = &entry.chunks {
+            // Unless they are the chunks of the stored body
+            let stored = self.get_body(hash)?.and_then(|body| body.chunks);
+            if stored.map_or(true, |stored| stored.key != chunks.key) {
+                let keys = chunks::chunk_keys(&chunks.key);
+                batch.delete_range_cf(&chunk_cf, keys.start, keys.end);
+            }
+        }
+        batch.put_cf(&cf, refs_key(hash), (refs + 1).to_le_bytes());
+        Ok(())
+    }
+    /// Adds dropping a reference to each of `hashes` to `batch`, deleting the bodies no entry
+    /// refers to anymore along with their chunks. `write_lock` has to be held until the batch is
+    /// written.
+    fn release_bodies(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        hashes: &[[u8; 32]],
+    ) -> Result<(), DBFailure> {
+        let (Some(cf), Some(chunk_cf)) = (self.db.cf_handle(&self.config.body_cf), self.db.cf_handle(&self.config.chunk_cf)) else {
+            return Err(DBFailure::CfError)};
+        let mut released: HashMap<[u8; 32], u64> = HashMap::new();
+        for hash in hashes {
+            *released.entry(*hash).or_default() += 1;
+        }
+        for (hash, count) in released {
+            let refs = self.body_refs(&hash)?;
+            if refs > count {
+                batch.put_cf(&cf, refs_key(&hash), (refs - count).to_le_bytes());
+                continue;
+            }
+            if let Some(PasteBody {
+                chunks: Some(chunks),
+                ..
+            }) = self.get_body(&hash)?
+            {
+                let keys = chunks::chunk_keys(&chunks.key);
+                batch.delete_range_cf(&chunk_cf, keys.start, keys.end);
+            }
+            batch.delete_cf(&cf, hash);
+            batch.delete_cf(&cf, refs_key(&hash));
+        }
+        Ok(())
+    }
+    pub fn put_chunk(&self, key: &str, index: u32, chunk: &[u8]) -> Result<(), DBFailure> {
+        let Some(cf) = self.db.cf_handle(&self.config.chunk_cf) else {
+            return Err(DBFailure::CfError)};
+        self.db
+            .put_cf(&cf, chunks::chunk_key(key, index), chunk)
+            .map_err(DBFailure::Error)
+    }
+    pub fn get_chunk(&self, key: &str, index: u32) -> Result<Option<Vec<u8>>, DBFailure> {
+        let Some(cf) = self.db.cf_handle(&self.config.chunk_cf) else {
+            return Err(DBFailure::CfError)};
+        self.db
+            .get_cf(&cf, chunks::chunk_key(key, index))
+            .map_err(DBFailure::Error)
+    }
+    pub fn delete_chunks(&self, key: &str) -> Result<(), DBFailure> {
+        let Some(cf) = self.db.cf_handle(&self.config.chunk_cf) else {
+            return Err(DBFailure::CfError)};
+        let keys = chunks::chunk_keys(key);
+        self.db
+            .delete_range_cf(&cf, keys.start, keys.end)
+            .map_err(DBFailure::Error)
+    }
+    /// Reads the whole contents of a paste into memory, chunks included
+    pub fn read_contents(&self, entry: &Entry) -> Result<Vec<u8>, DBFailure> {
+        let Some(chunks) = &entry.chunks else {
+            return Ok(entry.contents.clone())};
+        let mut contents = Vec::with_capacity(chunks.size as usize);
+        contents.extend_from_slice(&entry.contents);
+        for index in 0..chunks.count {
+            let Some(chunk) = self.get_chunk(&chunks.key, index)? else {
+                return Err(DBFailure::Corrupt)};
+            contents.extend_from_slice(&chunk);
         }
+        Ok(contents)
+    }
+    /// Reads the lines of a paste in `range`, from its chunks only up to the one the range ends in.
+    /// `None` if the paste ends before the range starts.
+    pub fn read_lines(
+        &self,
+        head: &[u8],
+        chunks: Option<&Chunks>,
+        range: LineRange,
+    ) -> Result<Option<Vec<u8>>, DBFailure> {
+        let mut slicer = LineSlicer::new(range);
+        let mut more = slicer.push(head);
+        if let Some(chunks) = chunks {
+            for index in 0..chunks.count {
+                if !more {
+                    break;
+                }
+                let Some(chunk) = self.get_chunk(&chunks.key, index)? else {
+                    return Err(DBFailure::Corrupt)};
+                more = slicer.push(&chunk);
+            }
+        }
+        Ok(slicer.finish())
     }
     pub fn get_bytes<'a, K>(&'a self, key: K, cf_name: &'a str) -> Option<Vec<u8>>
     where
@@ -77,40 +730,135 @@ impl CurState {
             return None};
         Some(value)
     }
-    pub fn get<'a, K>(&'a self, key: K, cf_name: &'a str) -> Option<Entry>
+    /// Gets an entry, loading its contents if they are stored as a body
+    pub fn get<'a, K>(&'a self, key: K, cf_name: &'a str) -> Result<Option<Entry>, DBFailure>
     where
         K: AsRef<[u8]>,
     {
-        let Some(cf) = self.db.cf_handle(cf_name)  else{
-            return None
-        };
-        let Ok(Some(value)) = self.db.get_cf(&cf, key) else {
-            return None
-        };
-        let Ok(entry) = (unsafe { rkyv::from_bytes_unchecked::<Entry>(&value) }) else {
-            return None
-        };
-        Some(entry)
+        let key = key.as_ref();
+        let Some(mut entry) = self.get_record(key, cf_name)? else {
+            return Ok(None)};
+        if let Some(hash) = entry.hash {
+            let Some(body) = self.get_body(&hash)? else {
+                tracing::error!(
+                    "The body of {} in {cf_name} is missing",
+                    String::from_utf8_lossy(key)
+                );
+                return Err(DBFailure::Corrupt)};
+            entry.contents = body.contents;
+            entry.chunks = body.chunks;
+        }
+        Ok(Some(entry))
     }
-    pub fn put_large<'a, K>(
-        &'a self,
-        key: K,
-        value: Entry,
-        cf_name: &'a str,
-    ) -> Result<(), DBFailure>
+    /// Gets an entry as it is stored, without loading its body
+    fn get_record<'a, K>(&'a self, key: K, cf_name: &'a str) -> Result<Option<Entry>, DBFailure>
     where
         K: AsRef<[u8]>,
     {
-        let Some(cf) = self.db.cf_handle(cf_name) else {
+        let Some(cf) = self.db.cf_handle(cf_name)  else{
             return Err(DBFailure::CfError)
         };
-        let Ok(value) = rkyv::to_bytes::<_, 4096>(&value) else {
-            return Err(DBFailure::SerError)
+        let key = key.as_ref();
+        let value = match self.db.get_pinned_cf(&cf, key) {
+            Err(error) => return Err(DBFailure::Error(error)),
+            Ok(None) => return Ok(None),
+            Ok(Some(value)) => value,
         };
-        match self.db.put_cf(&cf, key, value) {
-            Err(error) => Err(DBFailure::Error(error)),
-            Ok(_) => Ok(()),
+        match storage::decode(&value) {
+            Ok(decoded) => Ok(Some(decoded.entry)),
+            Err(error) => {
+                tracing::error!(
+                    "Failed to decode {} in {cf_name}: {error:?}",
+                    String::from_utf8_lossy(key)
+                );
+                Err(error)
+            }
+        }
+    }
+    /// Rewrites every record in a column family stored in an older format version, moving their
+    /// inline counters to the counters column family. Returns how many records were migrated.
+    pub fn migrate(&self, cf_name: &str) -> Result<usize, DBFailure> {
+        let Some(cf) = self.db.cf_handle(cf_name) else {
+            return Err(DBFailure::CfError)};
+        let mut migrated = 0;
+        for item in self.db.iterator_cf(&cf, rocksdb::IteratorMode::Start) {
+            let (key, value) = item.map_err(DBFailure::Error)?;
+            let decoded = match storage::decode(&value) {
+                Ok(decoded) => decoded,
+                Err(error) => {
+                    tracing::warn!(
+                        "Skipping {} in {cf_name} during migration: {error:?}",
+                        String::from_utf8_lossy(&key)
+                    );
+                    continue;
+                }
+            };
+            if !decoded.outdated() {
+                continue;
+            }
+            // The counters and the record are written together, so a crash in between can't
+            // count the views of a record twice when it is migrated again
+            let mut batch = rocksdb::WriteBatch::default();
+            if let Some(counters) = decoded.counters {
+                let Some(counter_cf) = self.db.cf_handle(&self.config.counter_cf) else {
+                    return Err(DBFailure::CfError)};
+                batch.merge_cf(&counter_cf, counter_key(cf_name, &key), counters.to_bytes());
+            }
+            let mut entry = decoded.entry;
+            if key.contains(&b'@') {
+                // Earlier revisions used to be stored next to their paste
+                let Some(revision_cf) = self.db.cf_handle(&self.config.revision_cf) else {
+                    return Err(DBFailure::CfError)};
+                let revision_key = counter_key(cf_name, &key);
+                batch.put_cf(&revision_cf, revision_key, storage::encode::<4096>(&entry)?);
+                batch.delete_cf(&cf, &key);
+            } else {
+                entry.revisions = self.legacy_revisions(&key, cf_name)?;
+                batch.put_cf(&cf, &key, storage::encode::<4096>(&entry)?);
+            }
+            self.db.write(batch).map_err(DBFailure::Error)?;
+            migrated += 1;
+        }
+        if migrated != 0 {
+            tracing::info!(
+                "Migrated {migrated} records in {cf_name} to format version {FORMAT_VERSION}"
+            );
         }
+        Ok(migrated)
+    }
+    /// How many earlier revisions of `key` are still stored next to it, as they were before
+    /// format version 8
+    fn legacy_revisions(&self, key: &[u8], cf_name: &str) -> Result<u32, DBFailure> {
+        let Some(cf) = self.db.cf_handle(cf_name) else {
+            return Err(DBFailure::CfError)};
+        let range = revision_range(key);
+        let mode = rocksdb::IteratorMode::From(&range.start, rocksdb::Direction::Forward);
+        let mut revisions = 0;
+        for item in self.db.iterator_cf(&cf, mode) {
+            let (key, _) = item.map_err(DBFailure::Error)?;
+            if *key >= *range.end {
+                break;
+            }
+            revisions += 1;
+        }
+        Ok(revisions)
+    }
+    /// Deletes every expired entry in a column family, returning how many were removed
+    pub fn reap_expired(&self, cf_name: &str) -> Result<usize, DBFailure> {
+        let Some(cf) = self.db.cf_handle(cf_name) else {
+            return Err(DBFailure::CfError)};
+        let mut removed = 0;
+        for item in self.db.iterator_cf(&cf, rocksdb::IteratorMode::Start) {
+            let (key, value) = item.map_err(DBFailure::Error)?;
+            let Ok(decoded) = storage::decode(&value) else {
+                continue
+            };
+            if decoded.entry.is_expired() {
+                self.delete(key, cf_name)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
     }
     pub fn put<'a, K>(&'a self, key: K, value: Entry, cf_name: &'a str) -> Result<(), DBFailure>
     where
@@ -118,11 +866,222 @@ impl CurState {
     {
         let Some(cf) = self.db.cf_handle(cf_name) else {
             return Err(DBFailure::CfError)};
-        let Ok(value) = rkyv::to_bytes::<_, 256>(&value) else {
-            return Err(DBFailure::SerError)};
-        match self.db.put_cf(&cf, key, value) {
-            Err(error) => Err(DBFailure::Error(error)),
-            Ok(_) => Ok(()),
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.put_cf(&cf, key, value.record()?);
+        let _guard = self.lock();
+        self.take_body(&mut batch, &value)?;
+        self.db.write(batch).map_err(DBFailure::Error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunks::ChunkWriter;
+
+    fn test_state(name: &str) -> CurState {
+        let config = Config {
+            db_path: std::env::temp_dir().join(format!("oxii_link-{name}-{}", std::process::id())),
+            ..Config::default()
+        };
+        let _ = DB::destroy(&rocksdb::Options::default(), &config.db_path);
+        let db_cache = rocksdb::Cache::new_lru_cache(128).unwrap();
+        CurState {
+            db: Arc::new(open_db(&config, &db_cache).unwrap()),
+            db_cache,
+            cache: AsyncCache::new(100, 1024, tokio::spawn).unwrap(),
+            cards: Arc::new(Mutex::new(HashMap::new())),
+            templates: Arc::new(Templates::load(&config).unwrap()),
+            themes: Arc::new(Themes::load(&config).unwrap()),
+            rate_limiter: Arc::new(RateLimiter::new(&config)),
+            config: Arc::new(config),
+            write_lock: Arc::new(Mutex::new(())),
         }
     }
+
+    #[test]
+    fn counters_round_trip() {
+        let counters = Counters {
+            views: 42,
+            scrapes: 7,
+            last_viewed: 1_676_000_000,
+        };
+        assert_eq!(Counters::from_bytes(&counters.to_bytes()), counters);
+        assert_eq!(Counters::from_bytes(&[]), Counters::default());
+    }
+
+    #[tokio::test]
+    async fn counting_views_preserves_entry() {
+        let state = test_state("counting_views_preserves_entry");
+        let paste_cf = state.config.paste_cf.clone();
+        let mut entry = Entry::new("some paste", true)
+            .with_delete_token("token")
+            .with_expiry(Some(i64::MAX));
+        entry.creationdate = 1_000_000;
+        state.put("abc", entry, &paste_cf).unwrap();
+
+        state.count_view("abc", &paste_cf, false).unwrap();
+        state.count_view("abc", &paste_cf, false).unwrap();
+        state.count_view("abc", &paste_cf, true).unwrap();
+
+        let entry = state.get("abc", &paste_cf).unwrap().unwrap();
+        assert_eq!(entry.contents, b"some paste");
+        assert_eq!(entry.creationdate, 1_000_000);
+        assert!(entry.encrypted);
+        assert_eq!(entry.expiry, Some(i64::MAX));
+        assert!(entry.check_delete_token("token"));
+
+        let counters = state.counters("abc", &paste_cf).unwrap();
+        assert_eq!((counters.views, counters.scrapes), (2, 1));
+        assert!(counters.last_viewed > entry.creationdate);
+
+        // Counters are only ever shared by entries with the same key in the same column family
+        let url_counters = state.counters("abc", &state.config.url_cf).unwrap();
+        assert_eq!(url_counters, Counters::default());
+    }
+
+    #[tokio::test]
+    async fn new_ids_never_collide() {
+        let state = test_state("new_ids_never_collide");
+        let paste_cf = state.config.paste_cf.clone();
+        assert!(state
+            .insert_if_absent("a", &Entry::new("first", false), &paste_cf)
+            .unwrap());
+        assert!(!state
+            .insert_if_absent("a", &Entry::new("second", false), &paste_cf)
+            .unwrap());
+        // Expired entries the reaper didn't get to yet are replaced
+        let expired = Entry::new("old", false).with_expiry(Some(1));
+        state.put("b", expired, &paste_cf).unwrap();
+        assert!(!state.key_exists("b", &paste_cf).unwrap());
+        assert!(state
+            .insert_if_absent("b", &Entry::new("new", false), &paste_cf)
+            .unwrap());
+        assert_eq!(state.get("b", &paste_cf).unwrap().unwrap().contents, b"new");
+
+        // Far more entries than there are IDs of length 1
+        let mut ids = std::collections::HashSet::new();
+        for n in 0..200 {
+            let id = state
+                .insert_new(&Entry::new(n.to_string(), false), &paste_cf, 1)
+                .unwrap();
+            assert!(ids.insert(id));
+        }
+        assert!(ids.iter().any(|id| id.len() > 1));
+        assert_eq!(
+            state.get("a", &paste_cf).unwrap().unwrap().contents,
+            b"first"
+        );
+    }
+
+    #[tokio::test]
+    async fn large_pastes_are_chunked() {
+        let mut state = test_state("large_pastes_are_chunked");
+        let config = Arc::get_mut(&mut state.config).unwrap();
+        config.chunk_size = 4;
+        config.max_paste_bytes = 16;
+
+        let mut writer = ChunkWriter::new(&state, false);
+        writer.write(b"0123456").await.unwrap();
+        writer.write(b"789abc").await.unwrap();
+        let (body, hash, truncated) = writer.finish().await.unwrap();
+        assert!(!truncated);
+        assert_eq!(hash, *blake3::hash(b"0123456789abc").as_bytes());
+        assert_eq!(body.contents, b"0123");
+        let chunks = body.chunks.unwrap();
+        assert_eq!((chunks.count, chunks.size), (3, 13));
+        let entry = Entry::new(body.contents, false).with_chunks(Some(chunks));
+        assert_eq!(state.read_contents(&entry).unwrap(), b"0123456789abc");
+
+        // Oversized pastes are rejected, or cut back to a character boundary if the client allows
+        let mut writer = ChunkWriter::new(&state, false);
+        assert!(writer.write(&[b'a'; 17]).await.is_err());
+        let mut writer = ChunkWriter::new(&state, false).with_limit(4);
+        assert!(writer.write(b"01234").await.is_err());
+        let mut writer = ChunkWriter::new(&state, true);
+        assert!(!writer.write("aaaaaaaaaaaaaaa€".as_bytes()).await.unwrap());
+        let (body, hash, truncated) = writer.finish().await.unwrap();
+        assert!(truncated);
+        assert_eq!(hash, *blake3::hash(&[b'a'; 15]).as_bytes());
+        let entry = Entry::new(body.contents, false).with_chunks(body.chunks);
+        assert_eq!(state.read_contents(&entry).unwrap(), [b'a'; 15]);
+    }
+
+    #[tokio::test]
+    async fn identical_pastes_share_their_body() {
+        let mut state = test_state("identical_pastes_share_their_body");
+        Arc::get_mut(&mut state.config).unwrap().chunk_size = 4;
+        let paste_cf = state.config.paste_cf.clone();
+        async fn upload(state: &CurState) -> (PasteBody, [u8; 32], bool) {
+            let mut writer = ChunkWriter::new(state, false);
+            writer.write(b"the same build log").await.unwrap();
+            writer.finish().await.unwrap()
+        }
+
+        let (body, hash, _) = upload(&state).await;
+        let key = body.chunks.as_ref().unwrap().key.clone();
+        let first = Entry::new(body.contents, false)
+            .with_chunks(body.chunks)
+            .with_hash(Some(hash));
+        state.put("a", first, &paste_cf).unwrap();
+        // The second upload's chunks are dropped in favour of the stored ones
+        let (body, _, _) = upload(&state).await;
+        let duplicate = body.chunks.as_ref().unwrap().key.clone();
+        let second = Entry::new(body.contents, false)
+            .with_chunks(body.chunks)
+            .with_hash(Some(hash));
+        state.put("b", second, &paste_cf).unwrap();
+        assert_eq!(state.get_chunk(&duplicate, 0).unwrap(), None);
+
+        let entry = state.get("b", &paste_cf).unwrap().unwrap();
+        assert_eq!(state.read_contents(&entry).unwrap(), b"the same build log");
+        state.delete("a", &paste_cf).unwrap();
+        assert!(state.get_body(&hash).unwrap().is_some());
+        state.delete("b", &paste_cf).unwrap();
+        assert!(state.get_body(&hash).unwrap().is_none());
+        assert_eq!(state.get_chunk(&key, 0).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn revisions_are_kept_and_deleted_together() {
+        let state = test_state("revisions_are_kept_and_deleted_together");
+        let paste_cf = state.config.paste_cf.clone();
+        let stored = |contents: &str| {
+            let hash = *blake3::hash(contents.as_bytes()).as_bytes();
+            Entry::new(contents, false).with_hash(Some(hash))
+        };
+        let revise = |contents| {
+            state
+                .put_revision("a", &mut stored(contents), &paste_cf)
+                .unwrap()
+        };
+        assert_eq!(revise("unused"), None);
+        state.put("a", stored("first"), &paste_cf).unwrap();
+        state.put("ab", stored("other"), &paste_cf).unwrap();
+        assert_eq!(revise("second"), Some(2));
+        assert_eq!(state.latest_revision("a", &paste_cf).unwrap(), 2);
+        assert_eq!(state.latest_revision("ab", &paste_cf).unwrap(), 1);
+        // Earlier revisions are kept out of the pastes, so they aren't counted as pastes
+        assert!(state.get_record("a@1", &paste_cf).unwrap().is_none());
+
+        let revision = |n| state.get_revision("a", n, &paste_cf).unwrap();
+        assert_eq!(revision(1).unwrap().contents, b"first");
+        assert_eq!(revision(2).unwrap().contents, b"second");
+        assert!(revision(3).is_none());
+        let latest = state.get("a", &paste_cf).unwrap().unwrap();
+        assert_eq!(latest.contents, b"second");
+
+        // A short link with the same ID has nothing to do with the paste's revisions
+        let url_cf = state.config.url_cf.clone();
+        let link = Entry::new("https://example.com", false);
+        state.put("a", link, &url_cf).unwrap();
+        state.delete("a", &url_cf).unwrap();
+        assert_eq!(revision(1).unwrap().contents, b"first");
+
+        state.delete("a", &paste_cf).unwrap();
+        assert!(revision(1).is_none());
+        let first = blake3::hash(b"first");
+        assert!(state.get_body(first.as_bytes()).unwrap().is_none());
+        assert!(state.get("ab", &paste_cf).unwrap().is_some());
+    }
 }
@@ -0,0 +1,463 @@
+use crate::chunks::Chunks;
+use crate::content_type;
+use crate::state::{Counters, DBFailure, Entry, PasteBody};
+use rkyv::{AlignedVec, Archive, Deserialize, Serialize};
+
+/// Every versioned record starts with these bytes, followed by the format version
+const MAGIC: &[u8; 3] = b"OXL";
+/// The format version records are written in
+pub const FORMAT_VERSION: u8 = 8;
+/// Paste bodies are versioned separately from entries
+const BODY_MAGIC: &[u8; 3] = b"OXB";
+const BODY_VERSION: u8 = 1;
+
+/// An entry decoded from a record of any format version
+pub struct Decoded {
+    pub entry: Entry,
+    /// The format version the record was stored in
+    pub version: u8,
+    /// Counters that were stored inline in the record by older format versions
+    pub counters: Option<Counters>,
+}
+
+impl Decoded {
+    /// Whether the record should be rewritten in the current format
+    pub fn outdated(&self) -> bool {
+        self.version != FORMAT_VERSION
+    }
+}
+
+/// Version 0, records written before the header existed, with view counters stored inline
+#[derive(Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+struct EntryV0 {
+    views: u32,
+    scrapes: u32,
+    contents: Vec<u8>,
+    creationdate: i64,
+    obfuscate: bool,
+}
+
+impl EntryV0 {
+    fn migrate(self) -> (Entry, Counters) {
+        let entry = Entry {
+            creationdate: self.creationdate,
+            ..Entry::new(self.contents, self.obfuscate)
+        };
+        let counters = Counters {
+            views: self.views as u64,
+            scrapes: self.scrapes as u64,
+            last_viewed: 0,
+        };
+        (entry, counters)
+    }
+}
+
+/// Version 1, before the original filename of uploads was kept
+#[derive(Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+struct EntryV1 {
+    contents: Vec<u8>,
+    creationdate: i64,
+    obfuscate: bool,
+    delete_hash: [u8; 32],
+    expiry: Option<i64>,
+    burn_after_reading: bool,
+}
+
+impl EntryV1 {
+    fn migrate(self) -> Entry {
+        EntryV2 {
+            contents: self.contents,
+            creationdate: self.creationdate,
+            obfuscate: self.obfuscate,
+            delete_hash: self.delete_hash,
+            expiry: self.expiry,
+            burn_after_reading: self.burn_after_reading,
+            filename: None,
+        }
+        .migrate()
+    }
+}
+
+/// Version 2, before the content type of pastes was stored
+#[derive(Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+struct EntryV2 {
+    contents: Vec<u8>,
+    creationdate: i64,
+    obfuscate: bool,
+    delete_hash: [u8; 32],
+    expiry: Option<i64>,
+    burn_after_reading: bool,
+    filename: Option<String>,
+}
+
+impl EntryV2 {
+    fn migrate(self) -> Entry {
+        EntryV3 {
+            content_type: content_type::detect(None, &self.contents),
+            contents: self.contents,
+            creationdate: self.creationdate,
+            obfuscate: self.obfuscate,
+            delete_hash: self.delete_hash,
+            expiry: self.expiry,
+            burn_after_reading: self.burn_after_reading,
+            filename: self.filename,
+        }
+        .migrate()
+    }
+}
+
+/// Version 3, before large pastes were split into chunks
+#[derive(Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+struct EntryV3 {
+    contents: Vec<u8>,
+    creationdate: i64,
+    obfuscate: bool,
+    delete_hash: [u8; 32],
+    expiry: Option<i64>,
+    burn_after_reading: bool,
+    filename: Option<String>,
+    content_type: String,
+}
+
+impl EntryV3 {
+    fn migrate(self) -> Entry {
+        EntryV4 {
+            contents: self.contents,
+            creationdate: self.creationdate,
+            obfuscate: self.obfuscate,
+            delete_hash: self.delete_hash,
+            expiry: self.expiry,
+            burn_after_reading: self.burn_after_reading,
+            filename: self.filename,
+            content_type: self.content_type,
+            chunks: None,
+        }
+        .migrate()
+    }
+}
+
+/// Version 4, before paste bodies were stored by their hash
+#[derive(Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+struct EntryV4 {
+    contents: Vec<u8>,
+    creationdate: i64,
+    obfuscate: bool,
+    delete_hash: [u8; 32],
+    expiry: Option<i64>,
+    burn_after_reading: bool,
+    filename: Option<String>,
+    content_type: String,
+    chunks: Option<Chunks>,
+}
+
+impl EntryV4 {
+    fn migrate(self) -> Entry {
+        EntryV5 {
+            contents: self.contents,
+            creationdate: self.creationdate,
+            encrypted: self.obfuscate,
+            delete_hash: self.delete_hash,
+            expiry: self.expiry,
+            burn_after_reading: self.burn_after_reading,
+            filename: self.filename,
+            content_type: self.content_type,
+            chunks: self.chunks,
+            hash: None,
+        }
+        .migrate()
+    }
+}
+
+/// Version 5, before pastes could be protected by a password
+#[derive(Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+struct EntryV5 {
+    contents: Vec<u8>,
+    creationdate: i64,
+    encrypted: bool,
+    delete_hash: [u8; 32],
+    expiry: Option<i64>,
+    burn_after_reading: bool,
+    filename: Option<String>,
+    content_type: String,
+    chunks: Option<Chunks>,
+    hash: Option<[u8; 32]>,
+}
+
+impl EntryV5 {
+    fn migrate(self) -> Entry {
+        EntryV6 {
+            contents: self.contents,
+            creationdate: self.creationdate,
+            encrypted: self.encrypted,
+            delete_hash: self.delete_hash,
+            expiry: self.expiry,
+            burn_after_reading: self.burn_after_reading,
+            filename: self.filename,
+            content_type: self.content_type,
+            chunks: self.chunks,
+            hash: self.hash,
+            password_hash: None,
+        }
+        .migrate()
+    }
+}
+
+/// Version 6, before the language of pastes was detected when they were created
+#[derive(Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+struct EntryV6 {
+    contents: Vec<u8>,
+    creationdate: i64,
+    encrypted: bool,
+    delete_hash: [u8; 32],
+    expiry: Option<i64>,
+    burn_after_reading: bool,
+    filename: Option<String>,
+    content_type: String,
+    chunks: Option<Chunks>,
+    hash: Option<[u8; 32]>,
+    password_hash: Option<String>,
+}
+
+impl EntryV6 {
+    fn migrate(self) -> Entry {
+        EntryV7 {
+            contents: self.contents,
+            creationdate: self.creationdate,
+            encrypted: self.encrypted,
+            delete_hash: self.delete_hash,
+            expiry: self.expiry,
+            burn_after_reading: self.burn_after_reading,
+            filename: self.filename,
+            content_type: self.content_type,
+            chunks: self.chunks,
+            hash: self.hash,
+            password_hash: self.password_hash,
+            language: None,
+        }
+        .migrate()
+    }
+}
+
+/// Version 7, before the number of earlier revisions was kept on the entry. They were stored in
+/// the same column family then, `CurState::migrate` moves and counts them.
+#[derive(Archive, Deserialize, Serialize)]
+#[archive(check_bytes)]
+struct EntryV7 {
+    contents: Vec<u8>,
+    creationdate: i64,
+    encrypted: bool,
+    delete_hash: [u8; 32],
+    expiry: Option<i64>,
+    burn_after_reading: bool,
+    filename: Option<String>,
+    content_type: String,
+    chunks: Option<Chunks>,
+    hash: Option<[u8; 32]>,
+    password_hash: Option<String>,
+    language: Option<String>,
+}
+
+impl EntryV7 {
+    fn migrate(self) -> Entry {
+        Entry {
+            contents: self.contents,
+            creationdate: self.creationdate,
+            encrypted: self.encrypted,
+            delete_hash: self.delete_hash,
+            expiry: self.expiry,
+            burn_after_reading: self.burn_after_reading,
+            filename: self.filename,
+            content_type: self.content_type,
+            chunks: self.chunks,
+            hash: self.hash,
+            password_hash: self.password_hash,
+            language: self.language,
+            revisions: 0,
+        }
+    }
+}
+
+/// Serializes an entry into a record of the current format version, `SCRATCH` being the size of
+/// the serializer's scratch space
+pub fn encode<const SCRATCH: usize>(entry: &Entry) -> Result<Vec<u8>, DBFailure> {
+    let Ok(body) = rkyv::to_bytes::<_, SCRATCH>(entry) else {
+        return Err(DBFailure::SerError)};
+    let mut record = Vec::with_capacity(MAGIC.len() + 1 + body.len());
+    record.extend_from_slice(MAGIC);
+    record.push(FORMAT_VERSION);
+    record.extend_from_slice(&body);
+    Ok(record)
+}
+
+/// Validates and deserializes a record of any known format version
+pub fn decode(record: &[u8]) -> Result<Decoded, DBFailure> {
+    let header = record
+        .strip_prefix(MAGIC)
+        .and_then(|rest| rest.split_first());
+    if let Some((&version, body)) = header {
+        let body = aligned(body);
+        let entry = match version {
+            FORMAT_VERSION => rkyv::from_bytes::<Entry>(&body).ok(),
+            1 => rkyv::from_bytes::<EntryV1>(&body)
+                .ok()
+                .map(EntryV1::migrate),
+            2 => rkyv::from_bytes::<EntryV2>(&body)
+                .ok()
+                .map(EntryV2::migrate),
+            3 => rkyv::from_bytes::<EntryV3>(&body)
+                .ok()
+                .map(EntryV3::migrate),
+            4 => rkyv::from_bytes::<EntryV4>(&body)
+                .ok()
+                .map(EntryV4::migrate),
+            5 => rkyv::from_bytes::<EntryV5>(&body)
+                .ok()
+                .map(EntryV5::migrate),
+            6 => rkyv::from_bytes::<EntryV6>(&body)
+                .ok()
+                .map(EntryV6::migrate),
+            7 => rkyv::from_bytes::<EntryV7>(&body)
+                .ok()
+                .map(EntryV7::migrate),
+            _ => None,
+        };
+        if let Some(entry) = entry {
+            return Ok(Decoded {
+                entry,
+                version,
+                counters: None,
+            });
+        }
+    }
+    // A version 0 record has no header, but its contents could start with one by chance, so
+    // this is tried for every record that didn't decode above
+    if let Ok(legacy) = rkyv::from_bytes::<EntryV0>(&aligned(record)) {
+        let (entry, counters) = legacy.migrate();
+        return Ok(Decoded {
+            entry,
+            version: 0,
+            counters: Some(counters),
+        });
+    }
+    match header {
+        Some((&version, _)) if version > FORMAT_VERSION => Err(DBFailure::UnknownVersion(version)),
+        _ => Err(DBFailure::Corrupt),
+    }
+}
+
+pub fn encode_body(body: &PasteBody) -> Result<Vec<u8>, DBFailure> {
+    let Ok(archived) = rkyv::to_bytes::<_, 256>(body) else {
+        return Err(DBFailure::SerError)};
+    let mut record = Vec::with_capacity(BODY_MAGIC.len() + 1 + archived.len());
+    record.extend_from_slice(BODY_MAGIC);
+    record.push(BODY_VERSION);
+    record.extend_from_slice(&archived);
+    Ok(record)
+}
+
+pub fn decode_body(record: &[u8]) -> Result<PasteBody, DBFailure> {
+    match record
+        .strip_prefix(BODY_MAGIC)
+        .and_then(|rest| rest.split_first())
+    {
+        Some((&BODY_VERSION, body)) => {
+            rkyv::from_bytes::<PasteBody>(&aligned(body)).map_err(|_| DBFailure::Corrupt)
+        }
+        Some((&version, _)) if version > BODY_VERSION => Err(DBFailure::UnknownVersion(version)),
+        _ => Err(DBFailure::Corrupt),
+    }
+}
+
+/// rkyv needs the archived data to be aligned, which the header and RocksDB don't guarantee
+fn aligned(bytes: &[u8]) -> AlignedVec {
+    let mut aligned = AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(bytes);
+    aligned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let entry = Entry::new("paste", false).with_expiry(Some(1_000));
+        let decoded = decode(&encode::<256>(&entry).unwrap()).unwrap();
+        assert!(!decoded.outdated());
+        assert_eq!(decoded.entry.contents, b"paste");
+        assert_eq!(decoded.entry.expiry, Some(1_000));
+    }
+
+    #[test]
+    fn migrates_version_0() {
+        let legacy = EntryV0 {
+            views: 3,
+            scrapes: 2,
+            contents: b"OXL\x01 looks like a header".to_vec(),
+            creationdate: 1_000_000,
+            obfuscate: false,
+        };
+        let record = rkyv::to_bytes::<_, 256>(&legacy).unwrap();
+        let decoded = decode(&record).unwrap();
+        assert!(decoded.outdated());
+        assert_eq!(decoded.entry.contents, legacy.contents);
+        assert_eq!(decoded.entry.creationdate, 1_000_000);
+        let counters = decoded.counters.unwrap();
+        assert_eq!((counters.views, counters.scrapes), (3, 2));
+    }
+
+    #[test]
+    fn migrates_version_1() {
+        let legacy = EntryV1 {
+            contents: b"paste".to_vec(),
+            creationdate: 1_000_000,
+            obfuscate: false,
+            delete_hash: [7; 32],
+            expiry: Some(2_000_000),
+            burn_after_reading: true,
+        };
+        let mut record = b"OXL\x01".to_vec();
+        record.extend_from_slice(&rkyv::to_bytes::<_, 256>(&legacy).unwrap());
+        let decoded = decode(&record).unwrap();
+        assert!(decoded.outdated());
+        assert_eq!(decoded.entry.delete_hash, [7; 32]);
+        assert_eq!(decoded.entry.expiry, Some(2_000_000));
+        assert!(decoded.entry.burn_after_reading);
+        assert_eq!(decoded.entry.filename, None);
+        assert_eq!(decoded.entry.content_type, "text/plain");
+        assert_eq!(decoded.entry.chunks, None);
+        assert_eq!(decoded.entry.password_hash, None);
+        assert_eq!(decoded.entry.language, None);
+        assert_eq!(decoded.entry.revisions, 0);
+    }
+
+    #[test]
+    fn body_round_trip() {
+        let body = PasteBody {
+            contents: b"paste".to_vec(),
+            chunks: None,
+        };
+        let decoded = decode_body(&encode_body(&body).unwrap()).unwrap();
+        assert_eq!(decoded.contents, b"paste");
+        assert!(matches!(
+            decode_body(b"OXB\x01garbage"),
+            Err(DBFailure::Corrupt)
+        ));
+    }
+
+    #[test]
+    fn rejects_corrupt_records() {
+        assert!(matches!(decode(b"OXL\x08garbage"), Err(DBFailure::Corrupt)));
+        assert!(matches!(
+            decode(b"OXL\xffgarbage"),
+            Err(DBFailure::UnknownVersion(0xff))
+        ));
+    }
+}
@@ -1,37 +1,115 @@
-use syntect::html::{ClassStyle, ClassedHTMLGenerator};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use axum::http::{header, HeaderMap};
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator};
 use syntect::parsing::{SyntaxReference, SyntaxSet};
 use syntect::util::LinesWithEndings;
 
+use crate::config::Config;
+use crate::lines::LineRange;
+use crate::util::{sanitize_html, Palette, THEME, THEMESET};
+
+/// The syntaxes pastes are highlighted with, set once at startup by `load_syntaxes`. Anything
+/// using them before that gets the defaults.
+static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+
+/// The slug of the hand written `files/maintheme.css`, shown when no other theme is picked
+pub const BUILTIN_THEME: &str = "oxiilink";
+/// The cookie the theme picked with `?theme=` is remembered in
+pub const THEME_COOKIE: &str = "theme";
+
+/// `theme` is the slug of one of `Themes`, `None` for the built-in stylesheet. With `lines` only
+/// those lines are shown, though everything before them is still highlighted for context.
 pub fn highlight_to_html(
     data: &str,
     ss: &SyntaxSet,
     syntax: &SyntaxReference,
+    theme: Option<&str>,
     extra: &str,
+    lines: Option<LineRange>,
 ) -> String {
     let mut html = String::with_capacity(data.len() + data.len() / 2 + 200 + extra.len());
-    html.push_str("<!DOCTYPE html>\n<html><head>\n<link rel=\"stylesheet\" href=\"/files/maintheme.css\"></head><body>");
+    html.push_str(
+        "<!DOCTYPE html>\n<html><head>\n<link rel=\"stylesheet\" href=\"/files/paste.css\">\n",
+    );
+    match theme {
+        Some(theme) => {
+            let _ = write!(
+                html,
+                "<link rel=\"stylesheet\" href=\"/theme/{theme}.css\">"
+            );
+        }
+        None => html.push_str("<link rel=\"stylesheet\" href=\"/files/maintheme.css\">"),
+    }
+    html.push_str("<script src=\"/files/lines.js\" defer></script></head><body>");
     html.push_str(extra);
+    let range = lines.unwrap_or(LineRange {
+        start: 1,
+        end: None,
+    });
     let mut html_generator =
         ClassedHTMLGenerator::new_with_class_style(syntax, &ss, ClassStyle::Spaced);
-    for line in LinesWithEndings::from(data) {
+    let mut parsed = 0;
+    for line in LinesWithEndings::from(data).take(range.end.unwrap_or(usize::MAX)) {
         html_generator
             .parse_html_for_line_which_includes_newline(line)
             .unwrap();
+        parsed += 1;
     }
     let html_n = html_generator.finalize();
 
     html.push_str("<pre class=\"code\">");
-    html_n.lines().for_each(|line| {
-        html.push_str("<i></i>");
+    // Spans can run over several lines, so the ones still open where the range starts are opened
+    // again there
+    let mut open = Vec::new();
+    for (index, line) in html_n.lines().enumerate() {
+        let number = index + 1;
+        if number < range.start {
+            open_spans(line, &mut open);
+            continue;
+        }
+        if number == range.start {
+            open.iter().for_each(|span| html.push_str(span));
+        }
+        // What is left after the last line only closes spans
+        if number > parsed {
+            html.push_str(line);
+            continue;
+        }
+        let _ = write!(
+            html,
+            "<a class=\"ln\" id=\"L{number}\" href=\"#L{number}\" data-line=\"{number}\"></a>"
+        );
         html.push_str(line);
         html.push('\n')
-    });
+    }
     html.push_str("</pre>\n</body></html>");
     // let Ok(html) = highlighted_html_for_string(data, ss, syntax, theme) else {
     //     return None
     // };
     html
 }
+/// Keeps track of the `<span>` tags left open at the end of a highlighted line
+fn open_spans<'a>(line: &'a str, open: &mut Vec<&'a str>) {
+    let mut rest = line;
+    while let Some(at) = rest.find('<') {
+        rest = &rest[at..];
+        if let Some(after) = rest.strip_prefix("</span>") {
+            open.pop();
+            rest = after;
+        } else if let Some(end) = rest.find('>') {
+            open.push(&rest[..=end]);
+            rest = &rest[end + 1..];
+        } else {
+            break;
+        }
+    }
+}
+
 // use crate::SYNTAXSET;
 // use crate::THEME;
 // pub fn text_for_image<'a>(
@@ -47,3 +125,305 @@ pub fn highlight_to_html(
 //     lines
 //     // .collect::<Vec<_>>();
 // }
+
+pub fn syntaxes() -> &'static SyntaxSet {
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+/// Adds the `.sublime-syntax` files in `syntaxes_dir` to the default syntaxes. Has to be called
+/// before `SYNTAXSET` is first used.
+pub fn load_syntaxes(config: &Config) -> Result<(), syntect::LoadingError> {
+    let Some(dir) = &config.syntaxes_dir else {
+        return Ok(())};
+    let syntaxes = compile_syntaxes(dir, &config.syntax_cache)?;
+    if SYNTAXES.set(syntaxes).is_err() {
+        tracing::warn!("Syntaxes were used before they were loaded, the extra ones are ignored");
+    }
+    Ok(())
+}
+
+/// Compiling syntaxes takes a while, so the result is dumped to `cache` along with a hash of the
+/// files it was compiled from, and loaded from there until one of them changes
+fn compile_syntaxes(dir: &Path, cache: &Path) -> Result<SyntaxSet, syntect::LoadingError> {
+    let fingerprint = fingerprint(dir).map_err(syntect::LoadingError::Io)?;
+    let cached = std::fs::read(cache).ok().and_then(|dump| {
+        let dump = dump.strip_prefix(fingerprint.as_slice())?;
+        syntect::dumps::from_reader(dump).ok()
+    });
+    if let Some(syntaxes) = cached {
+        return Ok(syntaxes);
+    }
+    let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+    builder.add_from_folder(dir, true)?;
+    let syntaxes = builder.build();
+    let mut dump = fingerprint.to_vec();
+    let written = syntect::dumps::dump_to_writer(&syntaxes, &mut dump)
+        .map_err(|error| error.to_string())
+        .and_then(|()| std::fs::write(cache, dump).map_err(|error| error.to_string()));
+    if let Err(error) = written {
+        tracing::warn!("Failed to cache the compiled syntaxes: {error}");
+    }
+    Ok(syntaxes)
+}
+
+/// A hash of the syntax files in `dir` and of the version of the server, as dumps written by
+/// other versions may not load
+fn fingerprint(dir: &Path) -> std::io::Result<[u8; 32]> {
+    let mut files = Vec::new();
+    syntax_files(dir, &mut files)?;
+    files.sort();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    for file in files {
+        hasher.update(file.to_string_lossy().as_bytes());
+        hasher.update(&std::fs::read(&file)?);
+    }
+    Ok(*hasher.finalize().as_bytes())
+}
+
+fn syntax_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_syntax = path
+            .extension()
+            .map_or(false, |ext| ext == "sublime-syntax");
+        if path.is_dir() {
+            syntax_files(&path, files)?;
+        } else if is_syntax {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// A highlighting theme pages can be shown in
+pub struct ThemeInfo {
+    /// The name it was loaded under, shown in the picker
+    pub name: String,
+    pub theme: Theme,
+    /// The colours of embed images drawn in the theme
+    pub palette: Palette,
+    /// Styles the classes `highlight_to_html` emits in the colours of the theme
+    pub css: String,
+}
+
+/// The themes of `THEMESET` and the `.tmTheme` files in `themes_dir`, by slug
+pub struct Themes {
+    themes: BTreeMap<String, ThemeInfo>,
+    /// The theme of clients that didn't pick one, `None` for the built-in stylesheet
+    default: Option<String>,
+}
+
+impl Themes {
+    pub fn load(config: &Config) -> Result<Themes, syntect::LoadingError> {
+        let mut loaded: Vec<(String, Theme)> = THEMESET
+            .themes
+            .iter()
+            .map(|(name, theme)| (name.clone(), theme.clone()))
+            .collect();
+        if let Some(dir) = &config.themes_dir {
+            loaded.extend(ThemeSet::load_from_folder(dir)?.themes);
+        }
+        let mut themes = BTreeMap::new();
+        for (name, theme) in loaded {
+            let css = match css_for_theme_with_class_style(&theme, ClassStyle::Spaced) {
+                Ok(css) => css,
+                Err(error) => {
+                    tracing::warn!("Skipping theme {name}: {error}");
+                    continue;
+                }
+            };
+            // The rest of the page has to match the background of the code
+            let css = match theme.settings.background {
+                Some(c) => format!(
+                    "body {{ background-color: #{:02x}{:02x}{:02x}; }}\n{css}",
+                    c.r, c.g, c.b
+                ),
+                None => css,
+            };
+            let info = ThemeInfo {
+                palette: Palette::from_theme(&theme),
+                name,
+                theme,
+                css,
+            };
+            themes.insert(slug(&info.name), info);
+        }
+        let default = config.default_theme.as_deref().map(slug);
+        if let Some(default) = &default {
+            if default != BUILTIN_THEME && !themes.contains_key(default) {
+                tracing::warn!("Unknown default theme {default}, using the built-in one");
+            }
+        }
+        let default = default.filter(|default| themes.contains_key(default));
+        Ok(Themes { themes, default })
+    }
+    pub fn get(&self, slug: &str) -> Option<&ThemeInfo> {
+        self.themes.get(slug)
+    }
+    /// Whether `slug` names a theme, including the built-in one
+    pub fn contains(&self, slug: &str) -> bool {
+        slug == BUILTIN_THEME || self.themes.contains_key(slug)
+    }
+    /// The theme a page is shown in: the one in the query string, else the one in the cookie of
+    /// the client, else the default. Unknown themes are ignored.
+    pub fn pick(&self, requested: Option<&str>, headers: &HeaderMap) -> Option<&str> {
+        let cookie = cookie(headers, THEME_COOKIE);
+        let requested = requested
+            .into_iter()
+            .chain(cookie.as_deref())
+            .find(|slug| self.contains(slug));
+        self.resolve(requested)
+    }
+    /// The theme named `requested`, or the default if there is no such theme
+    pub fn resolve(&self, requested: Option<&str>) -> Option<&str> {
+        match requested.map(|slug| (slug, self.themes.get_key_value(slug))) {
+            Some((BUILTIN_THEME, _)) => None,
+            Some((_, Some((slug, _)))) => Some(slug.as_str()),
+            _ => self.default.as_deref(),
+        }
+    }
+    /// The token colours and the palette of embed images drawn in a theme, `None` being the
+    /// built-in one
+    pub fn for_image(&self, slug: Option<&str>) -> (&Theme, Palette) {
+        match slug.and_then(|slug| self.themes.get(slug)) {
+            Some(info) => (&info.theme, info.palette),
+            None => (&*THEME, Palette::BUILTIN),
+        }
+    }
+    /// A form for the page chrome that reloads the page in another theme
+    /// The range of lines shown is passed on, so picking a theme doesn't bring back the others
+    pub fn picker(&self, current: Option<&str>, lines: Option<LineRange>) -> String {
+        let current = current.unwrap_or(BUILTIN_THEME);
+        let mut html = String::from("<form method=\"get\">");
+        if let Some(lines) = lines {
+            let _ = write!(
+                html,
+                "<input type=\"hidden\" name=\"lines\" value=\"{lines}\">"
+            );
+        }
+        html.push_str("<select name=\"theme\" title=\"Theme\" onchange=\"this.form.submit()\">");
+        let builtin = (BUILTIN_THEME, "OxiiLink");
+        let themes = self
+            .themes
+            .iter()
+            .map(|(slug, info)| (slug.as_str(), info.name.as_str()));
+        for (slug, name) in std::iter::once(builtin).chain(themes) {
+            let selected = if slug == current { " selected" } else { "" };
+            let _ = write!(
+                html,
+                "<option value=\"{slug}\"{selected}>{}</option>",
+                sanitize_html(name)
+            );
+        }
+        html.push_str(
+            "</select><noscript><button type=\"submit\">Apply</button></noscript></form>",
+        );
+        html
+    }
+}
+
+/// Lowercases a theme name and replaces everything but letters and digits with '-', so it can be
+/// used in URLs, cookies and ETags
+fn slug(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// The value of a cookie the client sent
+fn cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn picks_themes() {
+        assert_eq!(slug("Solarized (dark)"), "solarized-dark");
+        assert_eq!(slug("base16-ocean.dark"), "base16-ocean-dark");
+        let themes = Themes::load(&Config::default()).unwrap();
+        assert!(themes
+            .get("solarized-dark")
+            .unwrap()
+            .css
+            .contains(".comment"));
+        let mut headers = HeaderMap::new();
+        assert_eq!(themes.pick(None, &headers), None);
+        assert_eq!(themes.pick(Some("nope"), &headers), None);
+        let cookies = HeaderValue::from_static("a=b; theme=inspiredgithub");
+        headers.insert(header::COOKIE, cookies);
+        assert_eq!(themes.pick(None, &headers), Some("inspiredgithub"));
+        assert_eq!(
+            themes.pick(Some("solarized-dark"), &headers),
+            Some("solarized-dark")
+        );
+        assert_eq!(themes.pick(Some(BUILTIN_THEME), &headers), None);
+        assert_eq!(themes.resolve(Some("nope")), None);
+        assert_eq!(themes.for_image(None).1, Palette::BUILTIN);
+        let (_, palette) = themes.for_image(Some("inspiredgithub"));
+        assert_eq!(palette.background, image::Rgba([255, 255, 255, 255]));
+        assert!(themes
+            .picker(Some("inspiredgithub"), None)
+            .contains("\"inspiredgithub\" selected"));
+    }
+
+    #[test]
+    fn compiles_and_caches_syntaxes() {
+        let dir = std::env::temp_dir().join(format!("oxii_link-syntaxes-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        let syntax = "%YAML 1.2\n---\nname: Oxii\nfile_extensions: [oxii]\nscope: source.oxii\n\
+                      contexts:\n  main:\n    - match: '\\blet\\b'\n      scope: keyword.oxii\n";
+        let path = dir.join("nested/Oxii.sublime-syntax");
+        std::fs::write(&path, syntax).unwrap();
+        let cache = dir.join("syntaxes.packdump");
+        let compiled = compile_syntaxes(&dir, &cache).unwrap();
+        assert!(compiled.find_syntax_by_extension("oxii").is_some());
+        assert!(compiled.find_syntax_by_extension("rs").is_some());
+        let fingerprint = fingerprint(&dir).unwrap();
+        assert!(std::fs::read(&cache).unwrap().starts_with(&fingerprint));
+        let cached = compile_syntaxes(&dir, &cache).unwrap();
+        assert!(cached.find_syntax_by_extension("oxii").is_some());
+        // Changing a syntax invalidates the dump
+        std::fs::write(&path, syntax.replace("name: Oxii", "name: Oxii Two")).unwrap();
+        let recompiled = compile_syntaxes(&dir, &cache).unwrap();
+        let name = &recompiled.find_syntax_by_extension("oxii").unwrap().name;
+        assert_eq!(name, "Oxii Two");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn anchors_and_slices_lines() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let syntax = ss.find_syntax_by_extension("rs").unwrap();
+        let code = "/* one\ntwo\nthree */\nfn four() {}\n";
+        let html = highlight_to_html(code, &ss, syntax, None, "", None);
+        assert!(html.contains("<a class=\"ln\" id=\"L4\" href=\"#L4\" data-line=\"4\"></a>"));
+        assert!(!html.contains("id=\"L5\""));
+        let range = LineRange::parse("2-3");
+        let html = highlight_to_html(code, &ss, syntax, None, "", range);
+        assert!(!html.contains("id=\"L1\"") && !html.contains("id=\"L4\""));
+        // The comment opened on the first line is opened again before the second
+        let pre = &html[html.find("<pre").unwrap()..];
+        let before = &pre[..pre.find("id=\"L2\"").unwrap()];
+        assert!(before.contains("<span class=\"comment"));
+        assert_eq!(pre.matches("<span").count(), pre.matches("</span>").count());
+    }
+}
@@ -1,12 +1,18 @@
+use crate::api::{self, Info, DELETE_TOKEN_HEADER};
+use crate::config::Config;
 use crate::handlers_paste::{BACKGROUND, FOREGROUND, LOGOFONT};
+use crate::password::{self, basic_auth, password_required, Credentials};
+use crate::ratelimit::ClientIp;
 use crate::state::CurState;
-use crate::{StatusCode, UrlPath, FILES_DIR, IP, PASTE_CF, URL_CF};
+use crate::syntax::syntaxes;
+use crate::{StatusCode, UrlPath};
 use axum::extract::State;
-use axum::http::header::HeaderName;
+use axum::http::header::{self, HeaderName};
 use axum::http::HeaderMap;
 use axum::response::Html;
 use axum::{response::IntoResponse, routing::get_service};
-use chrono::{TimeZone, Utc};
+use axum::{Extension, Json};
+use chrono::{LocalResult, TimeZone, Utc};
 use html2text::from_read;
 use image::{ImageBuffer, Rgba, RgbaImage};
 use imageproc::drawing::{draw_line_segment_mut, draw_text_mut};
@@ -15,25 +21,27 @@ use memchr::memchr3;
 use regex::Regex;
 use rocksdb::properties::ESTIMATE_NUM_KEYS;
 use rusttype::Scale;
+use serde::Deserialize;
 use std::borrow::Cow;
 use std::fs::File;
 use std::io::Read;
-use syntect::highlighting::{Theme, ThemeSet};
+use std::path::Path;
+use syntect::highlighting::{Color, Theme, ThemeSet};
 use syntect::parsing::SyntaxSet;
 use tower_http::services::{ServeDir, ServeFile};
 
 pub fn make_descriptors(
-    opts: crate::rocksdb::Options,
+    opts: rocksdb::Options,
     cf_names: Vec<&str>,
-) -> Vec<crate::rocksdb::ColumnFamilyDescriptor> {
+) -> Vec<rocksdb::ColumnFamilyDescriptor> {
     cf_names
         .into_iter()
-        .map(|x| crate::rocksdb::ColumnFamilyDescriptor::new(x, opts.clone()))
+        .map(|x| rocksdb::ColumnFamilyDescriptor::new(x, opts.clone()))
         .collect()
 }
 
-pub fn serve() -> axum::routing::MethodRouter {
-    get_service(ServeDir::new(FILES_DIR)).handle_error(handle_error)
+pub fn serve(files_dir: &Path) -> axum::routing::MethodRouter {
+    get_service(ServeDir::new(files_dir)).handle_error(handle_error)
 }
 //
 pub fn serve_file(file: &str) -> axum::routing::MethodRouter {
@@ -44,31 +52,76 @@ async fn handle_error(_err: std::io::Error) -> impl IntoResponse {
     (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong...")
 }
 
-pub async fn get_entries(State(state): State<CurState>) -> Result<impl IntoResponse, StatusCode> {
-    let (Some(url_cf), Some(paste_cf)) = (state.db.cf_handle(URL_CF),state.db.cf_handle(PASTE_CF)) else {
+pub async fn get_entries(
+    headers: HeaderMap,
+    State(state): State<CurState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let (Some(url_cf), Some(paste_cf)) = (state.db.cf_handle(&state.config.url_cf),state.db.cf_handle(&state.config.paste_cf)) else {
             return Err(StatusCode::INTERNAL_SERVER_ERROR)
     };
     let (Ok(Some(url_count)), Ok(Some(paste_count))) = (state.db.property_int_value_cf(&url_cf,ESTIMATE_NUM_KEYS), state.db.property_int_value_cf(&paste_cf,ESTIMATE_NUM_KEYS)) else {
         return Err(StatusCode::INTERNAL_SERVER_ERROR)
     };
-    Ok(format!(
-        "Total URL Shortened: {url_count}\nTotal pastes hosted: {paste_count}"
-    ))
+    if ClientType::from(&headers) == ClientType::Json {
+        return Ok(Json(api::Count {
+            urls: url_count,
+            pastes: paste_count,
+        })
+        .into_response());
+    }
+    Ok(
+        format!("Total URL Shortened: {url_count}\nTotal pastes hosted: {paste_count}")
+            .into_response(),
+    )
+}
+
+/// Formats a unix timestamp for analytics, 0 meaning it never happened
+fn format_date(timestamp: i64) -> String {
+    match Utc.timestamp_opt(timestamp, 0) {
+        LocalResult::Single(date) if timestamp != 0 => date.format("%d/%m/%Y %H:%M").to_string(),
+        _ => "Never".to_string(),
+    }
 }
 
 pub async fn analytics_paste(
     UrlPath(paste): UrlPath<String>,
     headers: HeaderMap,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
     State(state): State<CurState>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let (paste, ext) = match paste.split_once('.') {
         Some((paste, ext)) => (paste, Some(ext)),
         None => (paste.as_str(), None),
     };
-    let Some(entry) = state.get(paste, PASTE_CF)  else {
+    let key = paste.to_owned();
+    let entry = state
+        .blocking(move |state| state.get(key, &state.config.paste_cf))
+        .await??;
+    let Some(entry) = entry else {
         return Err(StatusCode::NOT_FOUND)};
+    if entry.is_expired() {
+        return Err(StatusCode::GONE);
+    }
+    let password = basic_auth(&headers);
+    let credentials = Credentials {
+        ip,
+        password: password.as_deref(),
+    };
+    match password::unlock(&state, &entry, credentials).await {
+        Ok(true) => (),
+        Ok(false) => return Ok(password_required(false, password.is_some()).into_response()),
+        Err(limited) => return Ok(limited),
+    }
+    let key = paste.to_owned();
+    let counters = state
+        .blocking(move |state| state.counters(key, &state.config.paste_cf))
+        .await??;
     use ClientType::*;
     match ClientType::from(&headers) {
+        Json => {
+            let info = Info::paste(&state.config, paste, &entry, counters);
+            Ok(axum::Json(info).into_response())
+        }
         HTML => Ok(Html(format!(
             "<html><head>
 <meta name='author' content='CordlessCoder'>
@@ -79,40 +132,38 @@ written in Rust using Axum'>
 </head><body>
 Views: <a>{}</a><br />
 Scrapes: <a>{}</a><br />
-Created: <a>{}</a>
+Created: <a>{}</a><br />
+Last viewed: <a>{}</a>
 </body></html>",
-            entry.views,
-            entry.scrapes,
-            Utc.timestamp_opt(entry.creationdate, 0)
-                .unwrap()
-                .format("%d/%m/%Y %H:%M")
+            counters.views,
+            counters.scrapes,
+            format_date(entry.creationdate),
+            format_date(counters.last_viewed)
         ))
         .into_response()),
         NoHtml => Ok(format!(
-            "Views: {}\nScrapes: {}\nCreated: {}",
-            entry.views,
-            entry.scrapes,
-            Utc.timestamp_opt(entry.creationdate, 0)
-                .unwrap()
-                .format("%d/%m/%Y %H:%M")
+            "Views: {}\nScrapes: {}\nCreated: {}\nLast viewed: {}",
+            counters.views,
+            counters.scrapes,
+            format_date(entry.creationdate),
+            format_date(counters.last_viewed)
         )
         .into_response()),
         _ => Ok(new_embed(
             &format!("Paste analytics for {paste}"),
             "OxiiLink",
             &format!(
-                "Views: {}\nScrapes: {}\nCreated: {}",
-                entry.views,
-                entry.scrapes,
-                Utc.timestamp_opt(entry.creationdate, 0)
-                    .unwrap()
-                    .format("%d/%m/%Y %H:%M")
+                "Views: {}\nScrapes: {}\nCreated: {}\nLast viewed: {}",
+                counters.views,
+                counters.scrapes,
+                format_date(entry.creationdate),
+                format_date(counters.last_viewed)
             ),
-            &format!("{IP}/a/{paste}"),
+            &format!("{}/a/{paste}", state.config.public_url),
             120,
             &format!(
                 "{}/i/{paste}{}",
-                IP,
+                state.config.public_url,
                 ext.map(|x| format!(".{x}")).unwrap_or_default()
             ),
         )
@@ -124,10 +175,26 @@ pub async fn analytics_url(
     headers: HeaderMap,
     State(state): State<CurState>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let Some(entry) = state.get(&short, URL_CF)  else {
+    let key = short.clone();
+    let (entry, counters) = state
+        .blocking(move |state| {
+            let cf_name = &state.config.url_cf;
+            state
+                .get(&key, cf_name)
+                .and_then(|entry| Ok((entry, state.counters(&key, cf_name)?)))
+        })
+        .await??;
+    let Some(entry) = entry else {
         return Err(StatusCode::NOT_FOUND)};
+    if entry.is_expired() {
+        return Err(StatusCode::GONE);
+    }
     use ClientType::*;
     match ClientType::from(&headers) {
+        Json => {
+            let info = Info::url(&state.config, &short, &entry, counters);
+            Ok(axum::Json(info).into_response())
+        }
         HTML => Ok(Html(format!(
             "<html><head>
 <meta name='author' content='CordlessCoder'>
@@ -138,36 +205,34 @@ written in Rust using Axum'>
 </head><body>
 Views: <a>{}</a><br />
 Scrapes: <a>{}</a><br />
-Created: <a>{}</a>
+Created: <a>{}</a><br />
+Last viewed: <a>{}</a>
 </body></html>",
-            entry.views,
-            entry.scrapes,
-            Utc.timestamp_opt(entry.creationdate, 0)
-                .unwrap()
-                .format("%d/%m/%Y %H:%M")
+            counters.views,
+            counters.scrapes,
+            format_date(entry.creationdate),
+            format_date(counters.last_viewed)
         ))
         .into_response()),
         NoHtml => Ok(format!(
-            "Views: {}\nScrapes: {}\nCreated: {}",
-            entry.views,
-            entry.scrapes,
-            Utc.timestamp_opt(entry.creationdate, 0)
-                .unwrap()
-                .format("%d/%m/%Y %H:%M")
+            "Views: {}\nScrapes: {}\nCreated: {}\nLast viewed: {}",
+            counters.views,
+            counters.scrapes,
+            format_date(entry.creationdate),
+            format_date(counters.last_viewed)
         )
         .into_response()),
         _ => Ok(new_embed(
             &format!("Paste analytics for {short}"),
             "OxiiLink",
             &format!(
-                "Views: {}\nScrapes: {}\nCreated: {}",
-                entry.views,
-                entry.scrapes,
-                Utc.timestamp_opt(entry.creationdate, 0)
-                    .unwrap()
-                    .format("%d/%m/%Y %H:%M")
+                "Views: {}\nScrapes: {}\nCreated: {}\nLast viewed: {}",
+                counters.views,
+                counters.scrapes,
+                format_date(entry.creationdate),
+                format_date(counters.last_viewed)
             ),
-            &format!("{IP}/a/{short}"),
+            &format!("{}/a/{short}", state.config.public_url),
             120,
             "",
         )
@@ -175,44 +240,87 @@ Created: <a>{}</a>
     }
 }
 
-pub async fn web_short(headers: HeaderMap) -> impl IntoResponse {
+pub async fn web_short(headers: HeaderMap, State(state): State<CurState>) -> impl IntoResponse {
     use ClientType::*;
 
     match ClientType::from(&headers) {
-        HTML => WEB_SHORT.to_owned().into_response(),
-        NoHtml => HELLO.to_owned().into_response(),
-        _ => EMBED_SHORT.to_owned().into_response(),
+        HTML => state.templates.web_short.to_owned().into_response(),
+        NoHtml | Json => state.templates.hello.to_owned().into_response(),
+        _ => state.templates.embed_short.to_owned().into_response(),
     }
 }
 
-pub async fn web_analytics(headers: HeaderMap) -> impl IntoResponse {
+pub async fn web_analytics(headers: HeaderMap, State(state): State<CurState>) -> impl IntoResponse {
     use ClientType::*;
 
     match ClientType::from(&headers) {
-        HTML => WEB_ANALYTICS.to_owned().into_response(),
-        NoHtml => HELLO.to_owned().into_response(),
-        _ => EMBED_HELLO.to_owned().into_response(),
+        HTML => state.templates.web_analytics.to_owned().into_response(),
+        NoHtml | Json => state.templates.hello.to_owned().into_response(),
+        _ => state.templates.embed_hello.to_owned().into_response(),
     }
 }
 
-pub async fn web_paste(headers: HeaderMap) -> impl IntoResponse {
+pub async fn web_paste(headers: HeaderMap, State(state): State<CurState>) -> impl IntoResponse {
     use ClientType::*;
 
     match ClientType::from(&headers) {
-        HTML => WEB_PASTE.to_owned().into_response(),
-        NoHtml => HELLO.to_owned().into_response(),
-        _ => EMBED_PASTE.to_owned().into_response(),
+        HTML => state.templates.web_paste.to_owned().into_response(),
+        NoHtml | Json => state.templates.hello.to_owned().into_response(),
+        _ => state.templates.embed_paste.to_owned().into_response(),
     }
 }
 
-pub async fn not_found(headers: HeaderMap) -> impl IntoResponse {
+pub async fn not_found(headers: HeaderMap, State(state): State<CurState>) -> impl IntoResponse {
     use ClientType::*;
 
     match ClientType::from(&headers) {
-        HTML => HTML_NOT_FOUND.to_owned().into_response(),
+        HTML => state.templates.html_not_found.to_owned().into_response(),
         NoHtml => "Not Found.".into_response(),
-        _ => EMBED_NOT_FOUND.to_owned().into_response(),
+        Json => (StatusCode::NOT_FOUND, "Not Found.").into_response(),
+        _ => state.templates.embed_not_found.to_owned().into_response(),
+    }
+}
+
+/// Serves the stylesheet of a highlighting theme, as `/theme/<slug>.css`
+pub async fn theme_css(
+    UrlPath(name): UrlPath<String>,
+    State(state): State<CurState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let slug = name.strip_suffix(".css").unwrap_or(&name);
+    let Some(theme) = state.themes.get(slug) else {
+        return Err(StatusCode::NOT_FOUND)};
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/css; charset=utf-8"),
+            (header::CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        theme.css.clone(),
+    ))
+}
+
+/// Lists the languages pastes can be highlighted in, with the extensions that pick them
+pub async fn languages(headers: HeaderMap) -> impl IntoResponse {
+    let mut syntaxes: Vec<_> = SYNTAXSET
+        .syntaxes()
+        .iter()
+        .filter(|syntax| !syntax.hidden)
+        .collect();
+    syntaxes.sort_by_key(|syntax| syntax.name.to_lowercase());
+    if ClientType::from(&headers) == ClientType::Json {
+        let languages: Vec<api::Language> = syntaxes
+            .iter()
+            .map(|syntax| api::Language {
+                name: &syntax.name,
+                extensions: &syntax.file_extensions,
+            })
+            .collect();
+        return Json(languages).into_response();
     }
+    syntaxes
+        .iter()
+        .map(|syntax| format!("{}\t{}\n", syntax.name, syntax.file_extensions.join(" ")))
+        .collect::<String>()
+        .into_response()
 }
 
 pub fn new_embed(
@@ -250,6 +358,97 @@ pub fn new_embed(
     ))
 }
 
+#[derive(Deserialize)]
+pub struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Gets the token sent with a request, either in the `X-Delete-Token` header or the `token`
+/// query parameter
+pub fn request_token<'a>(headers: &'a HeaderMap, query: &'a TokenQuery) -> Option<&'a str> {
+    headers
+        .get(HeaderName::from_static(DELETE_TOKEN_HEADER))
+        .and_then(|token| token.to_str().ok())
+        .or(query.token.as_deref())
+}
+
+/// Options accepted in the query string when creating a paste or short link
+#[derive(Deserialize, Default)]
+pub struct CreateOptions {
+    pub ttl: Option<String>,
+    pub burn: Option<String>,
+    pub truncate: Option<String>,
+    pub encrypted: Option<String>,
+    /// Only read when creating a paste, see `password`
+    pub password: Option<String>,
+}
+
+/// Advertises the largest paste this server accepts, in bytes
+pub static MAX_PASTE_BYTES_HEADER: &str = "x-max-paste-bytes";
+
+/// Checks whether a boolean option was set, either in a header or the query string
+pub fn flag(headers: &HeaderMap, header: &'static str, query: Option<&str>) -> bool {
+    headers
+        .get(HeaderName::from_static(header))
+        .and_then(|value| value.to_str().ok())
+        .or(query)
+        .map_or(false, |value| {
+            matches!(value.trim(), "" | "1" | "true" | "yes")
+        })
+}
+
+/// Parses a duration like `90`, `15m`, `12h` or `7d` into seconds
+pub fn parse_ttl(ttl: &str) -> Option<u64> {
+    let ttl = ttl.trim();
+    let (number, unit) = match ttl.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => ttl.split_at(idx),
+        None => (ttl, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Works out when a new entry expires from the `X-TTL` header or `ttl` query parameter, falling
+/// back to the configured default TTL
+pub fn expiry_for(
+    headers: &HeaderMap,
+    options: &CreateOptions,
+    config: &Config,
+) -> Result<Option<i64>, (StatusCode, &'static str)> {
+    let requested = headers
+        .get(HeaderName::from_static("x-ttl"))
+        .and_then(|ttl| ttl.to_str().ok())
+        .or(options.ttl.as_deref());
+    let mut ttl = match requested {
+        Some(ttl) => parse_ttl(ttl).ok_or((
+            StatusCode::BAD_REQUEST,
+            "Invalid TTL, expected a duration like 3600, 30m, 12h or 7d",
+        ))?,
+        None => config.default_ttl,
+    };
+    if config.max_ttl != 0 && (ttl == 0 || ttl > config.max_ttl) {
+        if requested.is_some() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Requested TTL exceeds the maximum allowed by this server",
+            ));
+        }
+        ttl = config.max_ttl;
+    }
+    if ttl == 0 {
+        return Ok(None);
+    }
+    let ttl = i64::try_from(ttl).unwrap_or(i64::MAX);
+    Ok(Some(Utc::now().timestamp().saturating_add(ttl)))
+}
+
 // pub fn sanitize_html<'a, S: Into<Cow<'a, str>>>(input: S) -> Cow<'a, str> {
 //     let input = input.into();
 //     let Some(first) = memchr3(b'<', b'>', b'&', input.as_bytes()) else {
@@ -310,6 +509,25 @@ pub fn sanitize_html<'a, S: Into<Cow<'a, str>>>(input: S) -> Cow<'a, str> {
     Cow::Owned(unsafe { String::from_utf8_unchecked(output) })
 }
 
+/// Reduces an uploaded filename to its last path component, replacing every character that isn't
+/// safe in a quoted `Content-Disposition` filename. `None` if nothing is left of it.
+pub fn sanitize_filename(filename: &str) -> Option<String> {
+    let name = filename
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or_default();
+    let name: String = name
+        .chars()
+        .take(128)
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' | ' ' | '+' => c,
+            _ => '_',
+        })
+        .collect();
+    let name = name.trim_matches(|c| c == '.' || c == ' ');
+    (!name.is_empty()).then(|| name.to_string())
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ClientType {
     Discord,
@@ -319,11 +537,20 @@ pub enum ClientType {
     UnknownBot,
     NoHtml,
     HTML,
+    /// Asked for `application/json`, gets JSON documents from the API
+    Json,
 }
 
 impl From<&HeaderMap> for ClientType {
     fn from(headers: &HeaderMap) -> Self {
         use ClientType::*;
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or("");
+        if accept.contains("application/json") {
+            return Json;
+        }
         match headers.get(HeaderName::from_static("user-agent")) {
             Some(h_uagent) => {
                 let Ok(uagent) = h_uagent.to_str() else {
@@ -360,12 +587,12 @@ impl From<&HeaderMap> for ClientType {
     }
 }
 
-pub async fn help(headers: HeaderMap) -> impl IntoResponse {
+pub async fn help(headers: HeaderMap, State(state): State<CurState>) -> impl IntoResponse {
     use ClientType::*;
     match ClientType::from(&headers) {
-        NoHtml => HELLO.to_owned().into_response(),
-        HTML => HTML_HELLO.to_owned().into_response(),
-        _ => EMBED_HELLO.to_owned().into_response(),
+        NoHtml | Json => state.templates.hello.to_owned().into_response(),
+        HTML => state.templates.html_hello.to_owned().into_response(),
+        _ => state.templates.embed_hello.to_owned().into_response(),
     }
 }
 
@@ -391,78 +618,51 @@ where
 }
 
 lazy_static! {
-    pub static ref SYNTAXSET: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    /// The default syntaxes and any loaded from `syntaxes_dir`, see `syntax::load_syntaxes`
+    pub static ref SYNTAXSET: &'static SyntaxSet = syntaxes();
     pub static ref THEMESET: ThemeSet = ThemeSet::load_defaults();
     pub static ref THEME: Theme = THEMESET.themes["Solarized (dark)"].clone();
-    pub static ref EMBED_HELLO: Html<String> = Html({
-        let mut file = File::open(FILES_DIR.to_owned() + "/EMBED.html").unwrap();
-        let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
-        data.replace(r"{IP_ADDR}", IP)
-    },);
-    pub static ref EMBED_SHORT: Html<String> = Html({
-        let mut file = File::open(FILES_DIR.to_owned() + "/EMBED_SHORT.html").unwrap();
-        let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
-        data.replace(r"{IP_ADDR}", IP)
-    },);
-    pub static ref EMBED_PASTE: Html<String> = Html({
-        let mut file = File::open(FILES_DIR.to_owned() + "/EMBED_PASTE.html").unwrap();
-        let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
-        data.replace(r"{IP_ADDR}", IP)
-    },);
-    pub static ref EMBED_NOT_FOUND: Html<String> =
-        new_embed("Not Found", "OxiiLink", "", IP, 50, "");
-    pub static ref HTML_NOT_FOUND: Html<String> = Html({
-        let mut file = File::open(FILES_DIR.to_owned() + "/NOT_FOUND.html").unwrap();
-        let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
-        data.replace(r"{IP_ADDR}", IP)
-    },);
-    pub static ref HTML_HELLO: Html<String> = Html({
-        let mut file = File::open(FILES_DIR.to_owned() + "/HELLO.html").unwrap();
-        let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
-        data.replace(r"{IP_ADDR}", IP)
-    },);
-    pub static ref HELLO: String = {
-        let data = HTML_HELLO.0.to_owned().into_bytes();
-        html_to_text(&*data, 80)
-    };
-    pub static ref WEB_SHORT: Html<String> = Html({
-        let mut file = File::open(FILES_DIR.to_owned() + "/WEB_SHORT.html").unwrap();
-        let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
-        data.replace(r"{IP_ADDR}", IP)
-    },);
-    pub static ref WEB_ANALYTICS: Html<String> = Html({
-        let mut file = File::open(FILES_DIR.to_owned() + "/WEB_ANALYTICS.html").unwrap();
-        let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
-        data.replace(r"{IP_ADDR}", IP)
-    },);
-    pub static ref WEB_PASTE: Html<String> = Html({
-        let mut file = File::open(FILES_DIR.to_owned() + "/WEB_PASTE.html").unwrap();
-        let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
-        data.replace(r"{IP_ADDR}", IP)
-    },);
-    pub static ref NOT_FOUND_HTML: Html<String> = Html({
-        let mut file = File::open(FILES_DIR.to_owned() + "/NOT_FOUND.html").unwrap();
-        let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
-        data.replace(r"{IP_ADDR}", IP)
-    },);
-    pub static ref NOT_FOUND_EMBED: Html<String> = new_embed(
-        "Not Found",
-        "OxiiLink",
-        "Cound not find this item.",
-        IP,
-        50,
-        ""
-    );
 }
+
+/// HTML templates from the files directory, with `{IP_ADDR}` replaced by the public URL.
+pub struct Templates {
+    pub embed_hello: Html<String>,
+    pub embed_short: Html<String>,
+    pub embed_paste: Html<String>,
+    pub embed_not_found: Html<String>,
+    pub html_not_found: Html<String>,
+    pub html_hello: Html<String>,
+    pub hello: String,
+    pub web_short: Html<String>,
+    pub web_analytics: Html<String>,
+    pub web_paste: Html<String>,
+}
+
+impl Templates {
+    pub fn load(config: &Config) -> std::io::Result<Templates> {
+        let load = |name: &str| -> std::io::Result<Html<String>> {
+            let mut file = File::open(config.files_dir.join(name))?;
+            let mut data = String::new();
+            file.read_to_string(&mut data)?;
+            Ok(Html(data.replace(r"{IP_ADDR}", &config.public_url)))
+        };
+        let html_hello = load("HELLO.html")?;
+        let hello = html_to_text(html_hello.0.as_bytes(), 80);
+        Ok(Templates {
+            embed_hello: load("EMBED.html")?,
+            embed_short: load("EMBED_SHORT.html")?,
+            embed_paste: load("EMBED_PASTE.html")?,
+            embed_not_found: new_embed("Not Found", "OxiiLink", "", &config.public_url, 50, ""),
+            html_not_found: load("NOT_FOUND.html")?,
+            html_hello,
+            hello,
+            web_short: load("WEB_SHORT.html")?,
+            web_analytics: load("WEB_ANALYTICS.html")?,
+            web_paste: load("WEB_PASTE.html")?,
+        })
+    }
+}
+
 pub fn round(img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, radius: (u32, u32, u32, u32)) {
     let (width, height) = img.dimensions();
     assert!(radius.0 + radius.1 <= width);
@@ -579,29 +779,82 @@ fn border_radius(
     }
 }
 
-pub fn create_image(size: (u32, u32), padding: u32) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-    let mut image = RgbaImage::from_pixel(size.0, size.1, BACKGROUND);
+/// The colours of an embed image, taken from a highlighting theme so the card matches the code
+/// drawn on it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub background: Rgba<u8>,
+    /// The logo
+    pub accent: Rgba<u8>,
+    /// The syntax name and creation date
+    pub text: Rgba<u8>,
+    pub line_number: Rgba<u8>,
+    /// The lines separating the header and the gutter from the code
+    pub rule: Rgba<u8>,
+}
+
+impl Palette {
+    /// The card drawn with the built-in theme
+    pub const BUILTIN: Palette = Palette {
+        background: BACKGROUND,
+        accent: FOREGROUND,
+        text: Rgba([169, 177, 214, 255]),
+        line_number: FOREGROUND,
+        rule: Rgba([65, 72, 104, 255]),
+    };
+
+    /// Falls back to the built-in colours for whatever the theme leaves unset
+    pub fn from_theme(theme: &Theme) -> Palette {
+        let rgba = |color: Color| Rgba([color.r, color.g, color.b, 255]);
+        let settings = &theme.settings;
+        let background = settings.background.map_or(BACKGROUND, rgba);
+        let text = settings.foreground.map_or(Palette::BUILTIN.text, rgba);
+        // Themes rarely set a guide colour, a faint foreground looks the same
+        let rule = settings
+            .guide
+            .map_or_else(|| mix(text, background, 0.25), rgba);
+        Palette {
+            background,
+            accent: settings.accent.or(settings.caret).map_or(text, rgba),
+            text,
+            line_number: settings.gutter_foreground.map_or(text, rgba),
+            rule,
+        }
+    }
+}
+
+/// `amount` of `color` over `background`
+fn mix(color: Rgba<u8>, background: Rgba<u8>, amount: f32) -> Rgba<u8> {
+    let channel =
+        |i: usize| (color[i] as f32 * amount + background[i] as f32 * (1.0 - amount)).round() as u8;
+    Rgba([channel(0), channel(1), channel(2), 255])
+}
+
+/// The size of embed cards in pixels
+pub const SIZE: (i32, i32) = (1200, 600);
+
+pub fn create_image(
+    size: (u32, u32),
+    padding: u32,
+    palette: &Palette,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let mut image = RgbaImage::from_pixel(size.0, size.1, palette.background);
     let radius = 12;
     draw_text_mut(
         &mut image,
-        FOREGROUND,
+        palette.accent,
         (size.0 - padding - 330) as i32,
         padding as i32,
         Scale { x: 80.0, y: 80.0 },
         &LOGOFONT,
         "OxiiLink",
     );
-    draw_line_segment_mut(
-        &mut image,
-        (0.0, 80.0),
-        (size.0 as f32, 80.0),
-        Rgba([65, 72, 104, 255]),
-    );
+    draw_line_segment_mut(&mut image, (0.0, 80.0), (size.0 as f32, 80.0), palette.rule);
     draw_line_segment_mut(
         &mut image,
         (50.0, 80.0),
         (50.0, size.1 as f32),
-        Rgba([65, 72, 104, 255]),
+        palette.rule,
     );
     round(&mut image, (radius, radius, radius, radius));
     image